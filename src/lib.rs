@@ -1,6 +1,7 @@
-mod sim;
+pub mod sim;
 pub mod system;
 pub mod net;
 pub mod node;
+#[cfg(feature = "serde")]
 pub mod pynode;
 pub mod test;
\ No newline at end of file
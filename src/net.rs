@@ -1,35 +1,268 @@
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::hash::Hash;
+use std::rc::Rc;
 
-use crate::sim::{Actor, ActorContext};
-use crate::system::SysEvent;
+use rand::RngCore;
 
-pub struct Network {
+use crate::sim::{Actor, ActorContext, ActorId};
+use crate::system::{LogKind, LogRecord, Logger, SysEvent};
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageStats {
+    pub sent: u64,
+    pub received: u64,
+    pub dropped: u64,
+}
+
+const LATENCY_BUCKETS: [f64; 11] = [0.1, 0.2, 0.5, 1., 2., 5., 10., 20., 50., 100., f64::INFINITY];
+
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    // (upper bound, count) pairs
+    pub histogram: Vec<(f64, u64)>,
+    // all observed delays, only retained if enabled with `Network::set_keep_latency_samples`
+    pub samples: Option<Vec<f64>>,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min: f64::INFINITY,
+            max: 0.,
+            mean: 0.,
+            histogram: LATENCY_BUCKETS.iter().map(|b| (*b, 0)).collect(),
+            samples: None,
+        }
+    }
+}
+
+impl LatencyStats {
+    fn add(&mut self, delay: f64) {
+        self.count += 1;
+        self.min = self.min.min(delay);
+        self.max = self.max.max(delay);
+        self.mean += (delay - self.mean) / self.count as f64;
+        if let Some(bucket) = self.histogram.iter_mut().find(|(bound, _)| delay < *bound) {
+            bucket.1 += 1;
+        }
+        if let Some(samples) = self.samples.as_mut() {
+            samples.push(delay);
+        }
+    }
+
+    // Nearest-rank percentile of the retained samples, `p` is in [0, 100].
+    // Returns None if samples are not retained or there are none yet.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        let mut samples = self.samples.clone().filter(|s| !s.is_empty())?;
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let rank = (p.clamp(0., 100.) / 100. * samples.len() as f64).ceil() as usize;
+        Some(samples[rank.max(1) - 1])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TapEvent<M> {
+    Sent { time: f64, src: String, dest: String, msg: M },
+    Delivered { time: f64, src: String, dest: String, msg: M, delay: f64 },
+    Dropped { time: f64, src: String, dest: String, msg: M, reason: String },
+    Duplicated { time: f64, src: String, dest: String, msg: M, copies: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct DroppedMessage<M> {
+    pub time: f64,
+    pub src: String,
+    pub dest: String,
+    pub msg: M,
+    pub reason: String,
+}
+
+pub type TapFn<M> = Box<dyn FnMut(&TapEvent<M>)>;
+pub type DelayFn = Box<dyn FnMut(&mut dyn RngCore) -> f64>;
+pub type MessageDelayFn<M> = Box<dyn FnMut(&M, &mut dyn RngCore) -> f64>;
+pub type CorruptFn<M> = Box<dyn Fn(&M) -> M>;
+pub type ByzantineFn<M> = Box<dyn Fn(&M) -> Vec<M>>;
+pub type HopFn<M> = Box<dyn Fn(&M) -> Option<M>>;
+pub type FilterFn<M> = Box<dyn Fn(&M) -> bool>;
+pub type PriorityFn<M> = Box<dyn Fn(&M) -> i64>;
+
+// Hooks are shared between clones of the network, e.g. in system snapshots
+type SharedFn<M, T> = Rc<dyn Fn(&M) -> T>;
+
+// Messages delivered on each link, used for deduplication
+trait DeliveredSet<M> {
+    // Returns false if the message was already delivered on the link
+    fn insert(&mut self, src: &str, dest: &str, msg: &M) -> bool;
+    fn clone_box(&self) -> Box<dyn DeliveredSet<M>>;
+}
+
+impl<M: Hash + Eq + Clone + 'static> DeliveredSet<M> for HashSet<(String, String, M)> {
+    fn insert(&mut self, src: &str, dest: &str, msg: &M) -> bool {
+        HashSet::insert(self, (src.to_string(), dest.to_string(), msg.clone()))
+    }
+
+    fn clone_box(&self) -> Box<dyn DeliveredSet<M>> {
+        Box::new(self.clone())
+    }
+}
+
+impl<M> Clone for Box<dyn DeliveredSet<M>> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+pub trait Prioritized {
+    // Messages with higher priority leave a link's send queue first. Only links with a window
+    // (see `set_link_window`) have queues, priority doesn't affect delivery times otherwise
+    fn priority(&self) -> i64;
+}
+
+pub trait Ttl: Clone {
+    fn ttl(&self) -> Option<u32>;
+    fn with_ttl(&self, ttl: u32) -> Self;
+
+    // Returns the message with decremented TTL or None if the TTL has expired
+    fn hop(&self) -> Option<Self> {
+        match self.ttl() {
+            Some(0) => None,
+            Some(ttl) => Some(self.with_ttl(ttl - 1)),
+            None => Some(self.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Network<M> {
     min_delay: f64,
     max_delay: f64,
     drop_rate: f64,
     dupl_rate: f64,
+    dupl_count: (u32, u32),
     corrupt_rate: f64,
     crashed_nodes: HashSet<String>,
     drop_incoming: HashSet<String>,
     drop_outgoing: HashSet<String>,
     disabled_links: HashSet<(String, String)>,
+    // links disabled by `make_partition`, messages dropped on them are kept until `heal_partition`
+    partition_links: HashSet<(String, String)>,
+    // number of scheduled outages currently covering each link, kept apart from `disabled_links`
+    // so that the end of an outage doesn't enable a link disabled by other means
+    link_outages: HashMap<(String, String), u32>,
+    incoming_filters: HashMap<String, SharedFn<M, bool>>,
+    outgoing_filters: HashMap<String, SharedFn<M, bool>>,
+    delay_fn: Option<Rc<RefCell<DelayFn>>>,
+    message_delay_fn: Option<Rc<RefCell<MessageDelayFn<M>>>>,
+    link_delays: HashMap<(String, String), (f64, f64)>,
+    link_drop_rates: HashMap<(String, String), f64>,
+    link_bandwidths: HashMap<(String, String), f64>,
+    link_busy_until: HashMap<(String, String), f64>,
+    link_windows: HashMap<(String, String), usize>,
+    link_in_flight: HashMap<(String, String), usize>,
+    link_queues: HashMap<(String, String), VecDeque<M>>,
+    priority_fn: Option<SharedFn<M, i64>>,
+    next_delays: HashMap<(String, String), f64>,
+    jailed: HashMap<String, f64>,
+    next_drops: HashMap<(String, String), usize>,
+    forced_drops: HashMap<(String, String), VecDeque<bool>>,
+    forced_dupls: HashMap<(String, String), VecDeque<bool>>,
+    // permutation and the messages held until there are enough of them to apply it
+    link_permutations: HashMap<(String, String), (Vec<usize>, Vec<M>)>,
+    // messages released by a permutation are delivered no earlier than the previous one
+    delivery_floor: Option<f64>,
+    message_size: SharedFn<M, usize>,
+    corrupt_fn: Option<SharedFn<M, M>>,
+    fifo: bool,
+    link_last_delivery: HashMap<(String, String), f64>,
+    reorder_window: Option<usize>,
+    link_recent_deliveries: HashMap<(String, String), VecDeque<f64>>,
+    node_stats: HashMap<String, MessageStats>,
+    byzantine: HashMap<String, SharedFn<M, Vec<M>>>,
+    latency_stats: LatencyStats,
+    delivered_messages: Option<Box<dyn DeliveredSet<M>>>,
+    hop_fn: Option<SharedFn<M, Option<M>>>,
+    tap: Option<Rc<RefCell<TapFn<M>>>>,
+    partition_drops: Vec<DroppedMessage<M>>,
+    // kept only once `record_dropped_messages` is called
+    dropped_messages: Option<Vec<DroppedMessage<M>>>,
+    crash_after_sends: HashMap<String, u64>,
+    triggered_crashes: Vec<String>,
+    // drops are passed to the system's logger
+    logger: Option<Rc<RefCell<Logger>>>,
     message_count: u64,
+    delivered_count: u64,
+    dropped_count: u64,
+    byte_count: u64,
+}
+
+impl<M> Default for Network<M> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Network {
+impl<M> Network<M> {
     pub fn new() -> Self {
         Self {
             min_delay: 1.,
             max_delay: 1.,
             drop_rate: 0.,
             dupl_rate: 0.,
+            dupl_count: (2, 3),
             corrupt_rate: 0.,
             crashed_nodes: HashSet::new(),
             drop_incoming: HashSet::new(),
             drop_outgoing: HashSet::new(),
             disabled_links: HashSet::new(),
+            partition_links: HashSet::new(),
+            link_outages: HashMap::new(),
+            incoming_filters: HashMap::new(),
+            outgoing_filters: HashMap::new(),
+            delay_fn: None,
+            message_delay_fn: None,
+            link_delays: HashMap::new(),
+            link_drop_rates: HashMap::new(),
+            link_bandwidths: HashMap::new(),
+            link_busy_until: HashMap::new(),
+            link_windows: HashMap::new(),
+            link_in_flight: HashMap::new(),
+            link_queues: HashMap::new(),
+            priority_fn: None,
+            next_delays: HashMap::new(),
+            jailed: HashMap::new(),
+            next_drops: HashMap::new(),
+            forced_drops: HashMap::new(),
+            forced_dupls: HashMap::new(),
+            link_permutations: HashMap::new(),
+            delivery_floor: None,
+            message_size: Rc::new(|_| 1),
+            corrupt_fn: None,
+            fifo: false,
+            link_last_delivery: HashMap::new(),
+            reorder_window: None,
+            link_recent_deliveries: HashMap::new(),
+            node_stats: HashMap::new(),
+            byzantine: HashMap::new(),
+            latency_stats: LatencyStats::default(),
+            delivered_messages: None,
+            hop_fn: None,
+            tap: None,
+            partition_drops: Vec::new(),
+            dropped_messages: None,
+            crash_after_sends: HashMap::new(),
+            triggered_crashes: Vec::new(),
+            logger: None,
             message_count: 0,
+            delivered_count: 0,
+            dropped_count: 0,
+            byte_count: 0,
         }
     }
 
@@ -43,20 +276,195 @@ impl Network {
         self.max_delay = max_delay;
     }
 
+    pub fn set_delay_fn(&mut self, f: Option<DelayFn>) {
+        self.delay_fn = f.map(|f| Rc::new(RefCell::new(f)));
+    }
+
+    pub fn set_message_delay_fn(&mut self, f: Option<MessageDelayFn<M>>) {
+        self.message_delay_fn = f.map(|f| Rc::new(RefCell::new(f)));
+    }
+
+    pub fn set_link_delay(&mut self, from: &str, to: &str, delay: f64) {
+        self.link_delays.insert((from.to_string(), to.to_string()), (delay, delay));
+    }
+
+    pub fn set_link_delays(&mut self, from: &str, to: &str, min_delay: f64, max_delay: f64) {
+        self.link_delays.insert((from.to_string(), to.to_string()), (min_delay, max_delay));
+    }
+
+    pub fn get_link_delays(&self, from: &str, to: &str) -> Option<(f64, f64)> {
+        self.link_delays.get(&(from.to_string(), to.to_string())).copied()
+    }
+
+    // Mean delay of the link including jail delays, delay functions are not taken into account
+    pub fn expected_delay(&self, from: &str, to: &str) -> f64 {
+        let (min_delay, max_delay) = self.get_link_delays(from, to).unwrap_or((self.min_delay, self.max_delay));
+        (min_delay + max_delay) / 2. + self.jailed.get(from).unwrap_or(&0.) + self.jailed.get(to).unwrap_or(&0.)
+    }
+
+    pub fn set_link_bandwidth(&mut self, from: &str, to: &str, bytes_per_sec: f64) {
+        self.link_bandwidths.insert((from.to_string(), to.to_string()), bytes_per_sec);
+    }
+
+    pub fn set_link_window(&mut self, from: &str, to: &str, max: usize) {
+        assert!(max > 0, "Window of link {} -> {} must allow at least one message in flight", from, to);
+        self.link_windows.insert((from.to_string(), to.to_string()), max);
+    }
+
+    pub fn has_link_windows(&self) -> bool {
+        !self.link_windows.is_empty()
+    }
+
+    pub fn set_priority_fn(&mut self, f: Option<PriorityFn<M>>) {
+        self.priority_fn = f.map(Rc::from);
+    }
+
+    pub fn jail_node(&mut self, node_id: &str, extra_delay: f64) {
+        self.jailed.insert(node_id.to_string(), extra_delay);
+    }
+
+    pub fn unjail_node(&mut self, node_id: &str) {
+        self.jailed.remove(node_id);
+    }
+
+    pub fn delay_next(&mut self, from: &str, to: &str, extra_delay: f64) {
+        self.next_delays.insert((from.to_string(), to.to_string()), extra_delay);
+    }
+
+    pub fn drop_next(&mut self, from: &str, to: &str, count: usize) {
+        self.next_drops.insert((from.to_string(), to.to_string()), count);
+    }
+
+    // Each decision is used instead of the random drop for one message sent on the link
+    pub fn force_drop(&mut self, from: &str, to: &str, decisions: Vec<bool>) {
+        self.forced_drops.insert((from.to_string(), to.to_string()), decisions.into());
+    }
+
+    // Each decision is used instead of the random duplication for one message delivered on the link
+    pub fn force_dupl(&mut self, from: &str, to: &str, decisions: Vec<bool>) {
+        self.forced_dupls.insert((from.to_string(), to.to_string()), decisions.into());
+    }
+
+    pub fn set_message_size_fn(&mut self, f: Box<dyn Fn(&M) -> usize>) {
+        self.message_size = Rc::from(f);
+    }
+
+    pub fn set_logger(&mut self, logger: Option<Rc<RefCell<Logger>>>) {
+        self.logger = logger;
+    }
+
+    pub fn set_fifo(&mut self, enabled: bool) {
+        self.fifo = enabled;
+    }
+
+    // The next `perm.len()` messages on the link are held, then passed on so that
+    // the i-th one delivered is the `perm[i]`-th one sent
+    pub fn set_link_permutation(&mut self, from: &str, to: &str, perm: Vec<usize>) {
+        let mut sorted = perm.clone();
+        sorted.sort_unstable();
+        assert!(sorted.iter().enumerate().all(|(i, p)| i == *p), "{:?} is not a permutation", perm);
+        let link = (from.to_string(), to.to_string());
+        if perm.is_empty() {
+            self.link_permutations.remove(&link);
+        } else {
+            self.link_permutations.insert(link, (perm, Vec::new()));
+        }
+    }
+
+    pub fn set_reorder_window(&mut self, window: Option<usize>) {
+        self.reorder_window = window;
+        self.link_recent_deliveries.clear();
+    }
+
     pub fn set_drop_rate(&mut self, drop_rate: f64) {
         self.drop_rate = drop_rate;
     }
 
+    pub fn set_link_drop_rate(&mut self, from: &str, to: &str, drop_rate: f64) {
+        self.link_drop_rates.insert((from.to_string(), to.to_string()), drop_rate);
+    }
+
     pub fn set_dupl_rate(&mut self, dupl_rate: f64) {
         self.dupl_rate = dupl_rate;
     }
 
+    pub fn set_dupl_count(&mut self, min: u32, max: u32) {
+        self.dupl_count = (min, max);
+    }
+
     pub fn set_corrupt_rate(&mut self, corrupt_rate: f64) {
+        assert!(corrupt_rate == 0. || self.corrupt_fn.is_some(),
+                "Corrupt rate {} requires a corruption function, call set_corrupt_fn first", corrupt_rate);
         self.corrupt_rate = corrupt_rate;
     }
 
+    pub fn set_corrupt_fn(&mut self, f: CorruptFn<M>) {
+        self.corrupt_fn = Some(Rc::from(f));
+    }
+
+    pub fn make_byzantine(&mut self, node_id: &str, f: ByzantineFn<M>) {
+        self.byzantine.insert(node_id.to_string(), Rc::from(f));
+    }
+
+    pub fn set_hop_fn(&mut self, f: Option<HopFn<M>>) {
+        self.hop_fn = f.map(Rc::from);
+    }
+
+    pub fn set_tap(&mut self, f: Option<TapFn<M>>) {
+        self.tap = f.map(|f| Rc::new(RefCell::new(f)));
+    }
+
     pub fn node_crashed(&mut self, node_id: &str) {
         self.crashed_nodes.insert(node_id.to_string());
+        self.crash_after_sends.remove(node_id);
+    }
+
+    // Crashes the node once the network has received `n` more messages sent by it.
+    // Messages sent after the `n`-th one are discarded as coming from a crashed node.
+    pub fn crash_after_sends(&mut self, node_id: &str, n: u64) {
+        if n == 0 {
+            self.node_crashed(node_id);
+            self.triggered_crashes.push(node_id.to_string());
+        } else {
+            self.crash_after_sends.insert(node_id.to_string(), n);
+        }
+    }
+
+    // Returns the nodes crashed by `crash_after_sends` since the previous call.
+    pub fn take_triggered_crashes(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.triggered_crashes)
+    }
+
+    pub fn node_recovered(&mut self, node_id: &str) {
+        self.crashed_nodes.remove(node_id);
+    }
+
+    pub fn remove_node(&mut self, node_id: &str) {
+        let involves = |link: &(String, String)| link.0 == node_id || link.1 == node_id;
+        self.crashed_nodes.remove(node_id);
+        self.drop_incoming.remove(node_id);
+        self.drop_outgoing.remove(node_id);
+        self.incoming_filters.remove(node_id);
+        self.outgoing_filters.remove(node_id);
+        self.disabled_links.retain(|link| !involves(link));
+        self.partition_links.retain(|link| !involves(link));
+        self.link_outages.retain(|link, _| !involves(link));
+        self.link_delays.retain(|link, _| !involves(link));
+        self.link_drop_rates.retain(|link, _| !involves(link));
+        self.link_bandwidths.retain(|link, _| !involves(link));
+        self.link_busy_until.retain(|link, _| !involves(link));
+        self.link_windows.retain(|link, _| !involves(link));
+        self.link_in_flight.retain(|link, _| !involves(link));
+        self.link_queues.retain(|link, _| !involves(link));
+        self.next_delays.retain(|link, _| !involves(link));
+        self.next_drops.retain(|link, _| !involves(link));
+        self.forced_drops.retain(|link, _| !involves(link));
+        self.forced_dupls.retain(|link, _| !involves(link));
+        self.link_permutations.retain(|link, _| !involves(link));
+        self.link_last_delivery.retain(|link, _| !involves(link));
+        self.link_recent_deliveries.retain(|link, _| !involves(link));
+        self.byzantine.remove(node_id);
+        self.jailed.remove(node_id);
     }
 
     pub fn drop_incoming(&mut self, node_id: &str) {
@@ -65,6 +473,11 @@ impl Network {
 
     pub fn pass_incoming(&mut self, node_id: &str) {
         self.drop_incoming.remove(node_id);
+        self.incoming_filters.remove(node_id);
+    }
+
+    pub fn filter_incoming(&mut self, node_id: &str, f: FilterFn<M>) {
+        self.incoming_filters.insert(node_id.to_string(), Rc::from(f));
     }
 
     pub fn drop_outgoing(&mut self, node_id: &str) {
@@ -73,6 +486,11 @@ impl Network {
 
     pub fn pass_outgoing(&mut self, node_id: &str) {
         self.drop_outgoing.remove(node_id);
+        self.outgoing_filters.remove(node_id);
+    }
+
+    pub fn filter_outgoing(&mut self, node_id: &str, f: FilterFn<M>) {
+        self.outgoing_filters.insert(node_id.to_string(), Rc::from(f));
     }
 
     pub fn disconnect_node(&mut self, node_id: &str) {
@@ -81,8 +499,8 @@ impl Network {
     }
 
     pub fn connect_node(&mut self, node_id: &str) {
-        self.drop_incoming.remove(node_id);
-        self.drop_outgoing.remove(node_id);
+        self.pass_incoming(node_id);
+        self.pass_outgoing(node_id);
     }
 
     pub fn disable_link(&mut self, from: &str, to: &str) {
@@ -90,80 +508,466 @@ impl Network {
     }
 
     pub fn enable_link(&mut self, from: &str, to: &str) {
-        self.disabled_links.remove(&(from.to_string(), to.to_string()));
+        let link = (from.to_string(), to.to_string());
+        self.disabled_links.remove(&link);
+        self.partition_links.remove(&link);
+    }
+
+    pub fn begin_link_outage(&mut self, from: &str, to: &str) {
+        *self.link_outages.entry((from.to_string(), to.to_string())).or_insert(0) += 1;
+    }
+
+    // Ends one outage of the link, the link is up again once no outage covers it
+    // unless it was also disabled with `disable_link` or by a partition
+    pub fn end_link_outage(&mut self, from: &str, to: &str) {
+        let link = (from.to_string(), to.to_string());
+        if let Some(outages) = self.link_outages.get_mut(&link) {
+            *outages -= 1;
+            if *outages == 0 {
+                self.link_outages.remove(&link);
+            }
+        }
     }
 
+    // Links which are already disabled are not considered part of the partition
     pub fn make_partition(&mut self, group1: &[&str], group2: &[&str]) {
         for n1 in group1 {
             for n2 in group2 {
-                self.disabled_links.insert((n1.to_string(), n2.to_string()));
-                self.disabled_links.insert((n2.to_string(), n1.to_string()));
+                for link in [(n1.to_string(), n2.to_string()), (n2.to_string(), n1.to_string())] {
+                    if self.disabled_links.insert(link.clone()) {
+                        self.partition_links.insert(link);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn make_partitions(&mut self, groups: &[&[&str]]) {
+        for (i, group1) in groups.iter().enumerate() {
+            for group2 in groups.iter().skip(i + 1) {
+                self.make_partition(group1, group2);
             }
         }
     }
 
-    pub fn reset_network(&mut self) {
+    pub fn heal_partition(&mut self) -> Vec<DroppedMessage<M>> {
+        for link in self.partition_links.drain() {
+            self.disabled_links.remove(&link);
+        }
+        self.partition_drops.drain(..).collect()
+    }
+
+    // Messages held back by the network on the link: waiting for a permutation to fill up
+    // or for the link window to open
+    pub fn held_messages(&self, from: &str, to: &str) -> usize {
+        let link = (from.to_string(), to.to_string());
+        self.link_permutations.get(&link).map_or(0, |(_, held)| held.len())
+            + self.link_queues.get(&link).map_or(0, |queue| queue.len())
+    }
+
+    pub fn held_message_count(&self) -> usize {
+        self.link_permutations.values().map(|(_, held)| held.len()).sum::<usize>()
+            + self.link_queues.values().map(|queue| queue.len()).sum::<usize>()
+    }
+
+    // Returns the messages which were held back on links, they are never delivered
+    pub fn reset_network(&mut self, time: f64) -> Vec<DroppedMessage<M>> {
+        let mut held: Vec<((String, String), Vec<M>)> = self.link_queues.drain()
+            .map(|(link, queue)| (link, queue.into_iter().collect()))
+            .chain(self.link_permutations.drain().map(|(link, (_, msgs))| (link, msgs)))
+            .collect();
+        // queued messages have passed the link's permutation, so were sent before the ones it holds
+        held.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut discarded = Vec::new();
+        for ((src, dest), msgs) in held {
+            for msg in msgs {
+                discarded.push(DroppedMessage {
+                    time, src: src.clone(), dest: dest.clone(), msg, reason: "network reset".to_string()
+                });
+            }
+        }
+        self.link_windows.clear();
+        self.link_in_flight.clear();
         self.disabled_links.clear();
+        self.partition_links.clear();
+        self.link_outages.clear();
+        self.partition_drops.clear();
+        if let Some(dropped) = self.dropped_messages.as_mut() {
+            dropped.clear();
+        }
         self.drop_incoming.clear();
         self.drop_outgoing.clear();
+        self.incoming_filters.clear();
+        self.outgoing_filters.clear();
+        self.link_drop_rates.clear();
+        self.node_stats.clear();
+        let keep_samples = self.latency_stats.samples.is_some();
+        self.latency_stats = LatencyStats::default();
+        self.set_keep_latency_samples(keep_samples);
+        discarded
+    }
+
+    pub fn link_enabled(&self, from: &str, to: &str) -> bool {
+        !self.drop_outgoing.contains(from)
+            && !self.drop_incoming.contains(to)
+            && !self.disabled_links.contains(&(from.to_string(), to.to_string()))
+            && !self.link_outages.contains_key(&(from.to_string(), to.to_string()))
     }
 
     pub fn get_message_count(&self) -> u64 {
         self.message_count
     }
+
+    pub fn get_delivered_count(&self) -> u64 {
+        self.delivered_count
+    }
+
+    pub fn get_dropped_count(&self) -> u64 {
+        self.dropped_count
+    }
+
+    pub fn get_byte_count(&self) -> u64 {
+        self.byte_count
+    }
+
+    pub fn get_node_stats(&self, node_id: &str) -> MessageStats {
+        self.node_stats.get(node_id).cloned().unwrap_or_default()
+    }
+
+    pub fn set_keep_latency_samples(&mut self, enabled: bool) {
+        self.latency_stats.samples = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn get_latency_stats(&self) -> LatencyStats {
+        self.latency_stats.clone()
+    }
+
+    pub fn record_dropped_messages(&mut self) {
+        if self.dropped_messages.is_none() {
+            self.dropped_messages = Some(Vec::new());
+        }
+    }
+
+    pub fn get_dropped_messages(&self) -> Option<&[DroppedMessage<M>]> {
+        self.dropped_messages.as_deref()
+    }
 }
 
-impl<M: Debug + Clone> Actor<SysEvent<M>> for Network {
-    fn on(&mut self, event: SysEvent<M>, ctx: &mut ActorContext<SysEvent<M>>) {
-        match event {
-            SysEvent::MessageSend { msg, src, dest } => {
-                if !self.crashed_nodes.contains(&src.to()) {
-                    let mut drop = false;
-                    let mut reason: String = "".to_string();
-                    let randvalue = ctx.rand();
-                    if randvalue < self.drop_rate {
-                        reason = "random drop".to_string();
-                        drop = true;
+impl<M: Hash + Eq + Clone + 'static> Network<M> {
+    pub fn set_dedup(&mut self, enabled: bool) {
+        self.delivered_messages = if enabled {
+            Some(Box::new(HashSet::<(String, String, M)>::new()))
+        } else {
+            None
+        };
+    }
+}
+
+impl<M: Debug + Clone> Network<M> {
+    fn notify_tap<F: FnOnce() -> TapEvent<M>>(&mut self, f: F) {
+        if let Some(tap) = &self.tap {
+            (tap.borrow_mut())(&f());
+        }
+    }
+
+    fn log(&self, time: f64, node: &ActorId, kind: LogKind) {
+        if let Some(logger) = &self.logger {
+            (logger.borrow_mut())(&LogRecord { time, node: Some(node.to()), kind });
+        }
+    }
+
+    fn record_drop(&mut self, time: f64, src: &ActorId, dest: &ActorId, msg: &M, reason: &str) {
+        if let Some(dropped) = self.dropped_messages.as_mut() {
+            dropped.push(DroppedMessage {
+                time, src: src.to(), dest: dest.to(), msg: msg.clone(), reason: reason.to_string()
+            });
+        }
+    }
+
+    fn draw_delay(&self, msg: &M, src: &ActorId, dest: &ActorId, ctx: &mut ActorContext<SysEvent<M>>) -> f64 {
+        let delay = if let Some((min_delay, max_delay)) = self.get_link_delays(&src.to(), &dest.to()) {
+            min_delay + ctx.rand() * (max_delay - min_delay)
+        } else if let Some(delay_fn) = &self.message_delay_fn {
+            (delay_fn.borrow_mut())(msg, ctx.rng())
+        } else if let Some(delay_fn) = &self.delay_fn {
+            (delay_fn.borrow_mut())(ctx.rng())
+        } else {
+            self.min_delay + ctx.rand() * (self.max_delay - self.min_delay)
+        };
+        // jailed nodes are slowed down both ways
+        delay + self.jailed.get(&src.to()).unwrap_or(&0.) + self.jailed.get(&dest.to()).unwrap_or(&0.)
+    }
+
+    // Takes the first of the queued messages with the highest priority
+    fn pop_queued(&mut self, link: &(String, String)) -> Option<M> {
+        let queue = self.link_queues.get_mut(link)?;
+        let pos = match &self.priority_fn {
+            Some(priority) => queue.iter().enumerate()
+                .max_by_key(|(i, msg)| (priority(msg), std::cmp::Reverse(*i)))
+                .map(|(i, _)| i)?,
+            None => 0,
+        };
+        queue.remove(pos)
+    }
+
+    fn window_full(&self, link: &(String, String)) -> bool {
+        match self.link_windows.get(link) {
+            Some(max) => self.link_in_flight.get(link).copied().unwrap_or(0) >= *max,
+            None => false,
+        }
+    }
+
+    // Applies the link bandwidth and the delivery order rules to a drawn delay,
+    // the message is delivered no earlier than `min_arrival`
+    fn schedule_delivery(&mut self, msg: &M, link: &(String, String), delay: f64, min_arrival: f64,
+                         ctx: &mut ActorContext<SysEvent<M>>) -> f64 {
+        let mut delay = delay.max(min_arrival - ctx.time());
+        if let Some(bandwidth) = self.link_bandwidths.get(link) {
+            let arrival = ctx.time() + delay;
+            let busy_until = self.link_busy_until.get(link).copied().unwrap_or(arrival);
+            let finish = arrival.max(busy_until) + (self.message_size)(msg) as f64 / bandwidth;
+            self.link_busy_until.insert(link.clone(), finish);
+            delay = finish - ctx.time();
+        }
+        if self.fifo {
+            let last_delivery = self.link_last_delivery.get(link).copied().unwrap_or(0.);
+            delay = delay.max(last_delivery - ctx.time());
+            self.link_last_delivery.insert(link.clone(), ctx.time() + delay);
+        } else if let Some(window) = self.reorder_window {
+            // the message may overtake up to `window` previous messages on the link,
+            // but is delivered after all messages sent before them
+            let recent = self.link_recent_deliveries.entry(link.clone()).or_default();
+            let floor = self.link_last_delivery.get(link).copied().unwrap_or(0.);
+            let mut arrival = ctx.time() + delay;
+            let shift = ((ctx.rand() * (recent.len() + 1) as f64) as usize).min(recent.len());
+            if shift > 0 {
+                // strictly before the overtaken message, as events with equal times keep their order
+                let overtaken = recent[recent.len() - shift];
+                if overtaken > 0. {
+                    arrival = arrival.min(f64::from_bits(overtaken.to_bits() - 1));
+                }
+            }
+            arrival = arrival.max(floor).max(min_arrival);
+            // arrival times within the window are kept distinct, so that any of them can be overtaken
+            while recent.contains(&arrival) {
+                arrival = f64::from_bits(arrival.to_bits() + 1);
+            }
+            recent.push_back(arrival);
+            if recent.len() > window {
+                let oldest = recent.pop_front().unwrap();
+                self.link_last_delivery.insert(link.clone(), floor.max(oldest));
+            }
+            delay = arrival - ctx.time();
+        }
+        if let Some(floor) = self.delivery_floor {
+            delay = delay.max(floor - ctx.time());
+            self.delivery_floor = Some(ctx.time() + delay);
+        }
+        delay
+    }
+
+    fn process_message(&mut self, msg: M, src: ActorId, dest: ActorId, ctx: &mut ActorContext<SysEvent<M>>) {
+        let time = ctx.time();
+        self.node_stats.entry(src.to()).or_default().sent += 1;
+        self.notify_tap(|| TapEvent::Sent { time, src: src.to(), dest: dest.to(), msg: msg.clone() });
+        if !self.crashed_nodes.contains(&src.to()) {
+            let mut drop = false;
+            let mut reason: String = "".to_string();
+            let drop_rate = self.link_drop_rates.get(&(src.to(), dest.to())).copied().unwrap_or(self.drop_rate);
+            let randvalue = ctx.rand();
+            match self.next_drops.get_mut(&(src.to(), dest.to())) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    reason = "forced drop".to_string();
+                    drop = true;
+                }
+                _ => match self.forced_drops.get_mut(&(src.to(), dest.to())).and_then(|d| d.pop_front()) {
+                    Some(forced) => {
+                        reason = "forced drop".to_string();
+                        drop = forced;
                     }
-                    if self.drop_outgoing.contains(&src.to()) {
-                        reason = format!("{} is dropping outgoing", &src.to());
-                        drop = true;
+                    None => {
+                        if randvalue < drop_rate && !ctx.is_replaying() {
+                            reason = "random drop".to_string();
+                            drop = true;
+                        }
                     }
-                    if self.drop_incoming.contains(&dest.to()) {
-                        reason = format!("{} is dropping incoming", &dest.to());
+                }
+            }
+            if self.drop_outgoing.contains(&src.to()) {
+                reason = format!("{} is dropping outgoing", &src.to());
+                drop = true;
+            }
+            if self.drop_incoming.contains(&dest.to()) {
+                reason = format!("{} is dropping incoming", &dest.to());
+                drop = true;
+            }
+            if matches!(self.outgoing_filters.get(&src.to()), Some(filter) if !filter(&msg)) {
+                reason = format!("filtered by {} outgoing filter", &src.to());
+                drop = true;
+            }
+            if matches!(self.incoming_filters.get(&dest.to()), Some(filter) if !filter(&msg)) {
+                reason = format!("filtered by {} incoming filter", &dest.to());
+                drop = true;
+            }
+
+            let link = (src.to(), dest.to());
+            if self.disabled_links.contains(&link) || self.link_outages.contains_key(&link) {
+                reason = format!("link between {} and {} is broken", &src.to(), &dest.to());
+                drop = true;
+            }
+
+            let mut msg = msg;
+            if let Some(hop) = &self.hop_fn {
+                match hop(&msg) {
+                    Some(hopped) => msg = hopped,
+                    None => {
+                        reason = "TTL expired".to_string();
                         drop = true;
                     }
+                }
+            }
 
-                    if self.disabled_links.contains(&(src.to(), dest.to())) {
-                        reason = format!("link between {} and {} is broken", &src.to(), &dest.to());
-                        drop = true;
+            // suppressed duplicates are dropped before they take up the link
+            if !drop && self.delivered_messages.as_mut().map(|d| d.insert(&src.to(), &dest.to(), &msg)) == Some(false) {
+                reason = "duplicate".to_string();
+                drop = true;
+            }
+
+            if !drop
+            {
+                let mut delay = self.draw_delay(&msg, &src, &dest, ctx);
+                let link = (src.to(), dest.to());
+                if let Some(extra_delay) = self.next_delays.remove(&link) {
+                    delay += extra_delay;
+                }
+                let delay = self.schedule_delivery(&msg, &link, delay, ctx.time(), ctx);
+                if ctx.rand() < self.corrupt_rate {
+                    if let Some(corrupt) = &self.corrupt_fn {
+                        msg = corrupt(&msg);
                     }
+                }
+                let dupl_roll = ctx.rand();
+                let duplicate = match self.forced_dupls.get_mut(&(src.to(), dest.to())).and_then(|d| d.pop_front()) {
+                    Some(forced) => forced,
+                    None => dupl_roll < self.dupl_rate && !ctx.is_replaying(),
+                };
+                let copies = if !duplicate {
+                    1
+                } else {
+                    let (min, max) = self.dupl_count;
+                    let copies = (min + (ctx.rand() * (max - min + 1) as f64) as u32).min(max);
+                    // with dedup the extra copies are suppressed right away
+                    if self.delivered_messages.is_some() { 1 } else { copies }
+                };
+                self.notify_tap(|| TapEvent::Delivered {
+                    time, src: src.to(), dest: dest.to(), msg: msg.clone(), delay
+                });
+                if copies > 1 {
+                    self.notify_tap(|| TapEvent::Duplicated {
+                        time, src: src.to(), dest: dest.to(), msg: msg.clone(), copies
+                    });
+                }
+                self.byte_count += (self.message_size)(&msg) as u64 * copies as u64;
+                self.delivered_count += copies as u64;
+                let e = SysEvent::MessageReceive { msg: msg.clone(), src: src.clone(), dest: dest.clone() };
+                for i in 0..copies {
+                    // duplicates draw their own delays, but follow the same link rules
+                    // and never arrive before the original message
+                    let copy_delay = if i == 0 {
+                        delay
+                    } else {
+                        let copy_delay = self.draw_delay(&msg, &src, &dest, ctx);
+                        self.schedule_delivery(&msg, &link, copy_delay, ctx.time() + delay, ctx)
+                    };
+                    ctx.emit(e.clone(), dest.clone(), copy_delay);
+                    self.latency_stats.add(copy_delay);
+                }
+                if self.link_windows.contains_key(&(src.to(), dest.to())) {
+                    *self.link_in_flight.entry((src.to(), dest.to())).or_default() += 1;
+                    ctx.emit(SysEvent::MessageAcked { src, dest: dest.clone() }, ctx.id.clone(), delay);
+                }
+                self.node_stats.entry(dest.to()).or_default().received += copies as u64;
+            } else {
+                self.log(time, &src, LogKind::MessageDropped {
+                    dest: dest.to(), msg: format!("{:?}", msg), reason: reason.clone()
+                });
+                self.node_stats.entry(src.to()).or_default().dropped += 1;
+                self.dropped_count += 1;
+                self.record_drop(time, &src, &dest, &msg, &reason);
+                if self.partition_links.contains(&(src.to(), dest.to())) {
+                    self.partition_drops.push(DroppedMessage {
+                        time, src: src.to(), dest: dest.to(), msg: msg.clone(), reason: reason.clone()
+                    });
+                }
+                self.notify_tap(|| TapEvent::Dropped { time, src: src.to(), dest: dest.to(), msg, reason });
+            }
+        } else {
+            self.log(time, &src, LogKind::MessageDiscarded { msg: format!("{:?}", msg) });
+            self.record_drop(time, &src, &dest, &msg, &format!("{} is crashed", src.to()));
+            self.node_stats.entry(src.to()).or_default().dropped += 1;
+            self.dropped_count += 1;
+            self.notify_tap(|| TapEvent::Dropped {
+                time, src: src.to(), dest: dest.to(), msg, reason: format!("{} is crashed", src.to())
+            });
+        }
+        self.message_count += 1;
+    }
+}
 
-                    if !drop
-                    {
-                        let delay = self.min_delay + ctx.rand() * (self.max_delay - self.min_delay);
-                        if ctx.rand() < self.corrupt_rate {
-                            // TODO: support message corruption
-                        }
-                        let e = SysEvent::MessageReceive { msg, src, dest: dest.clone() };
-                        if ctx.rand() >= self.dupl_rate {
-                            ctx.emit(e, dest, delay);
-                        } else {
-                            let dups = (ctx.rand() * 2.).ceil() as u32 + 1;
-                            for _i in 0..dups {
-                                ctx.emit(e.clone(), dest.clone(), delay);
-                            }
+impl<M: Debug + Clone> Actor<SysEvent<M>> for Network<M> {
+    fn on(&mut self, event: SysEvent<M>, ctx: &mut ActorContext<SysEvent<M>>) {
+        if let SysEvent::MessageSend { msg, src, dest } = event {
+            let crash = match self.crash_after_sends.get_mut(&src.to()) {
+                Some(left) if !self.crashed_nodes.contains(&src.to()) => {
+                    *left -= 1;
+                    *left == 0
+                }
+                _ => false,
+            };
+            let msgs = match self.byzantine.get(&src.to()) {
+                Some(tamper) => tamper(&msg),
+                None => vec![msg],
+            };
+            for msg in msgs {
+                let link = (src.to(), dest.to());
+                let msgs = match self.link_permutations.get_mut(&link) {
+                    Some((perm, held)) => {
+                        held.push(msg);
+                        if held.len() < perm.len() {
+                            continue;
                         }
+                        let (perm, held) = self.link_permutations.remove(&link).unwrap();
+                        let mut held: Vec<Option<M>> = held.into_iter().map(Some).collect();
+                        perm.iter().map(|i| held[*i].take().unwrap()).collect()
+                    }
+                    None => vec![msg],
+                };
+                self.delivery_floor = if msgs.len() > 1 { Some(ctx.time()) } else { None };
+                for msg in msgs {
+                    if self.window_full(&link) {
+                        self.link_queues.entry(link.clone()).or_default().push_back(msg);
                     } else {
-                        println!("{:>9} {:>10} --x {:<10} {:?} <-- {}",
-                                 "!!!", src.to(), dest.to(), msg, &reason);
+                        self.process_message(msg, src.clone(), dest.clone(), ctx);
                     }
-                } else {
-                    println!("!!! Discarded message from crashed node {:?}", msg);
                 }
-                self.message_count += 1;
+                self.delivery_floor = None;
+            }
+            if crash {
+                self.node_crashed(&src.to());
+                self.triggered_crashes.push(src.to());
+            }
+        } else if let SysEvent::MessageAcked { src, dest } = event {
+            let link = (src.to(), dest.to());
+            if let Some(in_flight) = self.link_in_flight.get_mut(&link) {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+            while !self.window_full(&link) {
+                match self.pop_queued(&link) {
+                    Some(msg) => self.process_message(msg, src.clone(), dest.clone(), ctx),
+                    None => break,
+                }
             }
-            _ => (),
         }
     }
 
@@ -1,35 +1,104 @@
+use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::rc::Rc;
 
 use crate::sim::{Actor, ActorId, ActorContext};
-use crate::system::SysEvent;
+use crate::system::{LogKind, LogRecord, Logger, SysEvent};
 
 
-pub trait Node<M: Debug + Clone> {
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub trait Node<M: Debug + Clone>: AsAny {
     fn id(&self) -> &String;
     fn on_message(&mut self, msg: M, from: String, ctx: &mut Context<M>);
     fn on_local_message(&mut self, msg: M, ctx: &mut Context<M>);
     fn on_timer(&mut self, timer: String, ctx: &mut Context<M>);
+
+    // Brings the node back to its initial state, everything the node keeps in memory must be reset here.
+    // Called by `System::reset_node` and, unless `recover` is overridden, on recovery from a crash.
+    // NOTE: the default does nothing, so a node which doesn't override it keeps its whole state
+    // across crashes and resets
+    fn reset(&mut self) {}
+
+    // Called when the node recovers from a crash (see `System::recover_node`),
+    // before the persisted state is restored. Resets the node by default
+    fn recover(&mut self) {
+        self.reset();
+    }
+
+    // Logical cost of handling the event, accumulated per node (see `System::node_work`)
+    fn handler_cost(&self, _event: &SysEvent<M>) -> f64 {
+        1.0
+    }
+
+    // Whether the node has reached a terminal state, used for deadlock detection
+    fn is_done(&self) -> bool {
+        true
+    }
+
+    // Used by system snapshots. Nodes which don't implement these hooks
+    // keep their current state when a snapshot is restored.
+    fn clone_state(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+
+    fn restore_state(&mut self, _state: &dyn Any) {}
+
+    // Durable part of the node state which is saved by `Context::persist`
+    // and passed to `restore_persistent` after the node recovers from a crash,
+    // once `recover` has wiped the rest of the state.
+    fn persistent_state(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+
+    fn restore_persistent(&mut self, _state: &dyn Any) {}
+
+    // Independent copy of the node used by `System::fork`,
+    // usually `Some(Rc::new(RefCell::new(self.clone())))`
+    fn clone_node(&self) -> Option<Rc<RefCell<dyn Node<M>>>> {
+        None
+    }
+}
+
+pub enum NodeAction<M> {
+    Send { msg: M, dest: String },
+    Reply { msg: M, dest: String },
+    SendLocal { msg: M },
+    SendToClient { msg: M, client: String },
+    SetTimer { name: String, delay: f64, target: Option<String> },
+    CancelTimer { name: String, target: Option<String> },
+    CancelTimersWithPrefix { prefix: String },
+    Persist,
 }
 
-pub struct Context<'a, 'b, 'c, M: Debug + Clone> {
+pub struct Context<'a, 'b, M: Debug + Clone> {
     ctx: &'a mut ActorContext<'b, SysEvent<M>>,
-    timers: &'c mut HashMap<(ActorId, String), u64>,
-    local_events: &'c mut Vec<LocalEvent<M>>,
+    actions: Vec<NodeAction<M>>,
+    source: Option<String>,
+    client: Option<String>,
+    members: Option<Rc<RefCell<Vec<String>>>>,
+    logger: Option<Rc<RefCell<Logger>>>,
 }
 
-impl<'a, 'b, 'c, M: Debug + Clone> Context<'a, 'b, 'c, M> {
-    pub fn new(
-        ctx: &'a mut ActorContext<'b, SysEvent<M>>,
-        timers: &'c mut HashMap<(ActorId, String), u64>,
-        local_events: &'c mut Vec<LocalEvent<M>>,
-    ) -> Self {
+impl<'a, 'b, M: Debug + Clone> Context<'a, 'b, M> {
+    pub fn new(ctx: &'a mut ActorContext<'b, SysEvent<M>>) -> Self {
         Self {
             ctx,
-            timers,
-            local_events,
+            actions: Vec::new(),
+            source: None,
+            client: None,
+            members: None,
+            logger: None,
         }
     }
 
@@ -37,43 +106,88 @@ impl<'a, 'b, 'c, M: Debug + Clone> Context<'a, 'b, 'c, M> {
         self.ctx.time()
     }
 
-    pub fn send(&mut self, msg: M, dest: &str) {
-        let dest = ActorId::from(dest);
-        println!("{:>9.3} {:>10} --> {:<10} {:?}", self.ctx.time(), self.ctx.id.to(), dest.to(), msg);
-        if self.ctx.id == dest {
-            let event = SysEvent::MessageReceive { msg, src: self.ctx.id.clone(), dest: dest.clone() };
-            self.ctx.emit(event, dest, 0.0);
-        } else {
-            let event = SysEvent::MessageSend { msg, src: self.ctx.id.clone(), dest };
-            self.ctx.emit(event, ActorId::from("net"), 1.0);
+    fn log(&self, kind: LogKind) {
+        if let Some(logger) = &self.logger {
+            (logger.borrow_mut())(&LogRecord { time: self.ctx.time(), node: Some(self.ctx.id.to()), kind });
         }
     }
 
+    // Crashed nodes don't process events, so they can't send messages through the context
+    pub fn send(&mut self, msg: M, dest: &str) {
+        self.log(LogKind::Sent { dest: dest.to_string(), msg: format!("{:?}", msg) });
+        self.actions.push(NodeAction::Send { msg, dest: dest.to_string() });
+    }
+
+    // Ids of the nodes which are currently in the system and not crashed, including this one
+    pub fn members(&self) -> Vec<String> {
+        self.members.as_ref().map(|m| m.borrow().clone()).unwrap_or_default()
+    }
+
+    // Sender of the message being handled, None outside of `on_message`
+    pub fn source(&self) -> Option<&String> {
+        self.source.as_ref()
+    }
+
+    // Sends the message back to the sender of the message being handled,
+    // the system can then match it to the request (see `System::reply_pairs`)
+    pub fn reply(&mut self, msg: M) {
+        let dest = self.source.clone().expect("reply can only be used when handling a message");
+        self.log(LogKind::Sent { dest: dest.clone(), msg: format!("{:?}", msg) });
+        self.actions.push(NodeAction::Reply { msg, dest });
+    }
+
+    // When handling a message from a client (see `System::register_client`),
+    // the output is also delivered to that client
     pub fn send_local(&mut self, msg: M) {
-        println!("{:>9.3} {:>10} >>> {:<10} {:?}", self.ctx.time(), self.ctx.id.to(), "local", msg);
-        let event = LocalEvent {
-            time: self.time(),
-            msg: Some(msg),
-            tip: LocalEventType::LocalMessageSend
-        };
-        self.local_events.push(event);
+        self.log(LogKind::LocalSent { dest: "local".to_string(), msg: format!("{:?}", msg) });
+        self.actions.push(NodeAction::SendLocal { msg });
+    }
+
+    // Client which sent the local message being handled, None for other events
+    pub fn client(&self) -> Option<&String> {
+        self.client.as_ref()
+    }
+
+    // Delivers the output to the client, e.g. to answer its earlier request
+    pub fn send_to_client(&mut self, client: &str, msg: M) {
+        self.log(LogKind::LocalSent { dest: client.to_string(), msg: format!("{:?}", msg) });
+        self.actions.push(NodeAction::SendToClient { msg, client: client.to_string() });
     }
 
     pub fn set_timer(&mut self, name: &str, delay: f64) {
-        let event = SysEvent::TimerFired { name: name.to_string() };
-        let event_id = self.ctx.emit(event, self.ctx.id.clone(), delay);
-        self.timers.insert((self.ctx.id.clone(), name.to_string()), event_id);
+        self.actions.push(NodeAction::SetTimer { name: name.to_string(), delay, target: None });
+    }
+
+    // Sets a timer which fires at the target node but is owned (and can be canceled) by this node
+    pub fn set_timer_for(&mut self, target: &str, name: &str, delay: f64) {
+        self.actions.push(NodeAction::SetTimer { name: name.to_string(), delay, target: Some(target.to_string()) });
     }
 
     pub fn cancel_timer(&mut self, name: &str) {
-        if let Some(event_id) = self.timers.remove(&(self.ctx.id.clone(), name.to_string())) {
-            self.ctx.cancel_event(event_id);
-        }
+        self.actions.push(NodeAction::CancelTimer { name: name.to_string(), target: None });
+    }
+
+    pub fn cancel_timer_for(&mut self, target: &str, name: &str) {
+        self.actions.push(NodeAction::CancelTimer { name: name.to_string(), target: Some(target.to_string()) });
+    }
+
+    // Cancels all timers set by this node whose name starts with the prefix, e.g. `retransmit@`
+    pub fn cancel_timers_with_prefix(&mut self, prefix: &str) {
+        self.actions.push(NodeAction::CancelTimersWithPrefix { prefix: prefix.to_string() });
+    }
+
+    // Saves the node's persistent state as of the end of the current handler
+    pub fn persist(&mut self) {
+        self.actions.push(NodeAction::Persist);
     }
 
     pub fn rand(&mut self) -> f64 {
         self.ctx.rand()
     }
+
+    pub fn take_actions(&mut self) -> Vec<NodeAction<M>> {
+        self.actions.drain(..).collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -89,16 +203,58 @@ pub struct LocalEvent<M: Debug + Clone> {
     pub tip: LocalEventType
 }
 
+#[derive(Debug, Clone)]
+pub enum TimerEvent {
+    Set { time: f64, node: String, name: String, delay: f64 },
+    Fired { time: f64, node: String, name: String },
+    Cancelled { time: f64, node: String, name: String },
+}
+
+pub type TimerObserver = Box<dyn FnMut(&TimerEvent)>;
+
+// Output queues of the clients registered with `System::register_client`
+pub type ClientOutputs<M> = Rc<RefCell<HashMap<String, Vec<M>>>>;
+
+// How messages sent by a node to itself are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopbackMode {
+    // Passed through the network like any other message, so may be delayed or dropped
+    Network,
+    // Delivered immediately, never dropped (default)
+    Direct,
+}
+
+#[derive(Clone)]
 enum NodeStatus {
     Healthy,
     Crashed,
 }
 
+pub struct NodeSnapshot<M: Debug + Clone> {
+    timers: HashMap<(ActorId, String), u64>,
+    local_events: Vec<LocalEvent<M>>,
+    status: NodeStatus,
+    state: Option<Box<dyn Any>>,
+    persisted: Option<Rc<dyn Any>>,
+    work: f64,
+}
+
 pub struct NodeActor<M: Debug + Clone> {
     node: Rc<RefCell<dyn Node<M>>>,
     timers: HashMap<(ActorId, String), u64>,
     local_events: Vec<LocalEvent<M>>,
     status: NodeStatus,
+    loopback: LoopbackMode,
+    min_timer_delay: f64,
+    timer_observer: Option<Rc<RefCell<TimerObserver>>>,
+    persisted: Option<Rc<dyn Any>>,
+    replies: Option<Rc<RefCell<HashSet<u64>>>>,
+    work: f64,
+    members: Option<Rc<RefCell<Vec<String>>>>,
+    slowdown: f64,
+    clients: Option<ClientOutputs<M>>,
+    current_client: Option<String>,
+    logger: Option<Rc<RefCell<Logger>>>,
 }
 
 impl<M: Debug + Clone> NodeActor<M> {
@@ -108,15 +264,282 @@ impl<M: Debug + Clone> NodeActor<M> {
             timers: HashMap::new(),
             local_events: Vec::new(),
             status: NodeStatus::Healthy,
+            loopback: LoopbackMode::Direct,
+            min_timer_delay: 0.,
+            timer_observer: None,
+            persisted: None,
+            replies: None,
+            work: 0.,
+            members: None,
+            slowdown: 1.,
+            clients: None,
+            current_client: None,
+            logger: None,
+        }
+    }
+
+    pub fn set_members(&mut self, members: Option<Rc<RefCell<Vec<String>>>>) {
+        self.members = members;
+    }
+
+    // Outputs for clients are appended to their queues in `clients`, unknown clients are ignored
+    pub fn set_client_registry(&mut self, clients: Option<ClientOutputs<M>>) {
+        self.clients = clients;
+    }
+
+    fn send_to_client(&self, client: &str, msg: M) {
+        if let Some(clients) = &self.clients {
+            if let Some(outputs) = clients.borrow_mut().get_mut(client) {
+                outputs.push(msg);
+            }
         }
     }
 
+    // Ids of events sent with `Context::reply` are added to `replies`
+    pub fn set_reply_registry(&mut self, replies: Option<Rc<RefCell<HashSet<u64>>>>) {
+        self.replies = replies;
+    }
+
+    // Records about the node, e.g. the events it handles, the messages it sends
+    // or clamped timer delays, are passed to the system's logger
+    pub fn set_logger(&mut self, logger: Option<Rc<RefCell<Logger>>>) {
+        self.logger = logger;
+    }
+
+    fn log(&self, ctx: &ActorContext<SysEvent<M>>, kind: LogKind) {
+        if let Some(logger) = &self.logger {
+            (logger.borrow_mut())(&LogRecord { time: ctx.time(), node: Some(ctx.id.to()), kind });
+        }
+    }
+
+    pub fn set_timer_observer(&mut self, observer: Option<Rc<RefCell<TimerObserver>>>) {
+        self.timer_observer = observer;
+    }
+
+    fn notify_timer_observer(&self, event: TimerEvent) {
+        if let Some(observer) = &self.timer_observer {
+            (observer.borrow_mut())(&event);
+        }
+    }
+
+    pub fn set_loopback_mode(&mut self, mode: LoopbackMode) {
+        self.loopback = mode;
+    }
+
+    pub fn set_min_timer_delay(&mut self, min: f64) {
+        self.min_timer_delay = min;
+    }
+
+    // Delays of timers and sends scheduled by the node are multiplied by `factor`
+    pub fn set_slowdown(&mut self, factor: f64) {
+        self.slowdown = factor;
+    }
+
     pub fn get_local_events(&self) -> Vec<LocalEvent<M>> {
         self.local_events.clone()
     }
 
-    pub fn crash(&mut self) {
+    pub fn take_local_events(&mut self) -> Vec<LocalEvent<M>> {
+        self.local_events.drain(..).collect()
+    }
+
+    pub fn snapshot(&self) -> NodeSnapshot<M> {
+        NodeSnapshot {
+            timers: self.timers.clone(),
+            local_events: self.local_events.clone(),
+            status: self.status.clone(),
+            state: self.node.borrow().clone_state(),
+            persisted: self.persisted.clone(),
+            work: self.work,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &NodeSnapshot<M>) {
+        self.timers = snapshot.timers.clone();
+        self.local_events = snapshot.local_events.clone();
+        self.status = snapshot.status.clone();
+        self.persisted = snapshot.persisted.clone();
+        self.work = snapshot.work;
+        if let Some(state) = &snapshot.state {
+            self.node.borrow_mut().restore_state(state.as_ref());
+        }
+    }
+
+    // Copy of the actor with the same timers and settings driving another node
+    pub fn fork(&self, node: Rc<RefCell<dyn Node<M>>>) -> Self {
+        Self {
+            node,
+            timers: self.timers.clone(),
+            local_events: self.local_events.clone(),
+            status: self.status.clone(),
+            loopback: self.loopback,
+            min_timer_delay: self.min_timer_delay,
+            timer_observer: self.timer_observer.clone(),
+            persisted: self.persisted.clone(),
+            replies: self.replies.clone(),
+            work: self.work,
+            members: self.members.clone(),
+            slowdown: self.slowdown,
+            clients: self.clients.clone(),
+            current_client: None,
+            logger: self.logger.clone(),
+        }
+    }
+
+    pub fn work(&self) -> f64 {
+        self.work
+    }
+
+    // Timers of a crashed node are lost, they are returned for the caller to cancel
+    pub fn crash(&mut self) -> Vec<((ActorId, String), u64)> {
         self.status = NodeStatus::Crashed;
+        self.timers.drain().collect()
+    }
+
+    // Returns the timers set on the node's behalf while it was crashed, which the caller must cancel
+    pub fn recover(&mut self) -> Vec<((ActorId, String), u64)> {
+        self.node.borrow_mut().recover();
+        if let Some(state) = &self.persisted {
+            self.node.borrow_mut().restore_persistent(state.as_ref());
+        }
+        self.status = NodeStatus::Healthy;
+        self.timers.drain().collect()
+    }
+
+    // Resets the node and returns its timers, which the caller must cancel
+    pub fn reset(&mut self) -> Vec<((ActorId, String), u64)> {
+        self.node.borrow_mut().reset();
+        self.timers.drain().collect()
+    }
+
+    pub fn register_timer(&mut self, name: &str, event_id: u64) -> Option<u64> {
+        let id = self.node.borrow().id().clone();
+        self.register_timer_for(&id, name, event_id)
+    }
+
+    pub fn register_timer_for(&mut self, target: &str, name: &str, event_id: u64) -> Option<u64> {
+        self.timers.insert((ActorId::from(target), name.to_string()), event_id)
+    }
+
+    pub fn unregister_timer(&mut self, name: &str) -> Option<u64> {
+        let id = self.node.borrow().id().clone();
+        self.unregister_timer_for(&id, name)
+    }
+
+    pub fn unregister_timer_for(&mut self, target: &str, name: &str) -> Option<u64> {
+        self.timers.remove(&(ActorId::from(target), name.to_string()))
+    }
+
+    // Forgets the timer set on the target once it fires, unless it has been set again since
+    pub fn timer_fired(&mut self, target: &str, name: &str, event_id: u64) {
+        let key = (ActorId::from(target), name.to_string());
+        if self.timers.get(&key) == Some(&event_id) {
+            self.timers.remove(&key);
+        }
+    }
+
+    // Removes the timers whose name starts with the prefix, ordered by event id
+    pub fn unregister_timers_with_prefix(&mut self, prefix: &str) -> Vec<((ActorId, String), u64)> {
+        let mut removed: Vec<((ActorId, String), u64)> = self.timers.iter()
+            .filter(|((_, name), _)| name.starts_with(prefix))
+            .map(|(key, event_id)| (key.clone(), *event_id))
+            .collect();
+        removed.sort_by_key(|(_, event_id)| *event_id);
+        for (key, _) in &removed {
+            self.timers.remove(key);
+        }
+        removed
+    }
+
+    fn call_node<F>(&mut self, ctx: &mut ActorContext<SysEvent<M>>, source: Option<String>, f: F)
+        where F: FnOnce(&mut dyn Node<M>, &mut Context<M>)
+    {
+        let mut node_ctx = Context::new(ctx);
+        node_ctx.source = source;
+        node_ctx.client = self.current_client.clone();
+        node_ctx.members = self.members.clone();
+        node_ctx.logger = self.logger.clone();
+        f(&mut *self.node.borrow_mut(), &mut node_ctx);
+        let actions = node_ctx.take_actions();
+        self.handle_actions(actions, ctx);
+    }
+
+    fn send(&self, msg: M, dest: &str, ctx: &mut ActorContext<SysEvent<M>>) -> u64 {
+        debug_assert!(matches!(self.status, NodeStatus::Healthy), "Crashed node {} is sending", ctx.id);
+        let dest = ActorId::from(dest);
+        if ctx.id == dest && self.loopback == LoopbackMode::Direct {
+            let event = SysEvent::MessageReceive { msg, src: ctx.id.clone(), dest: dest.clone() };
+            ctx.emit(event, dest, 0.0)
+        } else {
+            let event = SysEvent::MessageSend { msg, src: ctx.id.clone(), dest };
+            ctx.emit(event, ActorId::from("net"), self.slowdown)
+        }
+    }
+
+    fn handle_actions(&mut self, actions: Vec<NodeAction<M>>, ctx: &mut ActorContext<SysEvent<M>>) {
+        for action in actions {
+            match action {
+                NodeAction::Send { msg, dest } => {
+                    self.send(msg, &dest, ctx);
+                }
+                NodeAction::Reply { msg, dest } => {
+                    let event_id = self.send(msg, &dest, ctx);
+                    if let Some(replies) = &self.replies {
+                        replies.borrow_mut().insert(event_id);
+                    }
+                }
+                NodeAction::SendLocal { msg } => {
+                    if let Some(client) = &self.current_client {
+                        self.send_to_client(client, msg.clone());
+                    }
+                    self.local_events.push(LocalEvent {
+                        time: ctx.time(),
+                        msg: Some(msg),
+                        tip: LocalEventType::LocalMessageSend
+                    });
+                }
+                NodeAction::SendToClient { msg, client } => {
+                    self.send_to_client(&client, msg);
+                }
+                NodeAction::SetTimer { name, delay, target } => {
+                    let target = target.map(|t| ActorId::from(&t)).unwrap_or_else(|| ctx.id.clone());
+                    let delay = delay * self.slowdown;
+                    let delay = if delay < self.min_timer_delay {
+                        self.log(ctx, LogKind::TimerDelayClamped {
+                            name: name.clone(), delay, min: self.min_timer_delay
+                        });
+                        self.min_timer_delay
+                    } else {
+                        delay
+                    };
+                    let event = SysEvent::TimerFired { name: name.clone(), owner: ctx.id.clone() };
+                    let event_id = ctx.emit(event, target.clone(), delay);
+                    if let Some(old_event_id) = self.timers.insert((target.clone(), name.clone()), event_id) {
+                        ctx.cancel_event(old_event_id);
+                        self.notify_timer_observer(TimerEvent::Cancelled {
+                            time: ctx.time(), node: target.to(), name: name.clone()
+                        });
+                    }
+                    self.notify_timer_observer(TimerEvent::Set { time: ctx.time(), node: target.to(), name, delay });
+                }
+                NodeAction::Persist => {
+                    self.persisted = self.node.borrow().persistent_state().map(Rc::from);
+                }
+                NodeAction::CancelTimer { name, target } => {
+                    let target = target.map(|t| ActorId::from(&t)).unwrap_or_else(|| ctx.id.clone());
+                    if let Some(event_id) = self.timers.remove(&(target.clone(), name.clone())) {
+                        self.notify_timer_observer(TimerEvent::Cancelled { time: ctx.time(), node: target.to(), name });
+                        ctx.cancel_event(event_id);
+                    }
+                }
+                NodeAction::CancelTimersWithPrefix { prefix } => {
+                    for ((target, name), event_id) in self.unregister_timers_with_prefix(&prefix) {
+                        self.notify_timer_observer(TimerEvent::Cancelled { time: ctx.time(), node: target.to(), name });
+                        ctx.cancel_event(event_id);
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -124,27 +547,42 @@ impl<M: Debug + Clone> Actor<SysEvent<M>> for NodeActor<M> {
     fn on(&mut self, event: SysEvent<M>, ctx: &mut ActorContext<SysEvent<M>>) {
         match self.status {
             NodeStatus::Healthy => {
+                if matches!(event, SysEvent::MessageReceive { .. } | SysEvent::LocalMessageReceive { .. }
+                    | SysEvent::TimerFired { .. } | SysEvent::PeriodicTimerFired { .. }) {
+                    self.work += self.node.borrow().handler_cost(&event);
+                }
                 match event {
-                    SysEvent::MessageReceive { msg, src, dest } => {
-                        println!("{:>9.3} {:>10} <-- {:<10} {:?}", ctx.time(), dest.to(), src.to(), msg);
-                        let mut node_ctx = Context::new(ctx, &mut self.timers, &mut self.local_events);
-                        self.node.borrow_mut().on_message(msg, src.to(), &mut node_ctx);
+                    SysEvent::MessageReceive { msg, src, .. } => {
+                        self.log(ctx, LogKind::Received { src: src.to(), msg: format!("{:?}", msg) });
+                        self.call_node(ctx, Some(src.to()), |node, node_ctx| node.on_message(msg, src.to(), node_ctx));
                     }
                     SysEvent::LocalMessageReceive { msg } => {
-                        println!("{:>9.3} {:>10} <<< {:<10} {:?}", ctx.time(), ctx.id.to(), "local", msg);
+                        self.log(ctx, LogKind::LocalReceived { msg: format!("{:?}", msg) });
                         self.local_events.push(LocalEvent {
                             time: ctx.time(),
                             msg: Some(msg.clone()),
                             tip: LocalEventType::LocalMessageReceive
                         });
-                        let mut node_ctx = Context::new(ctx, &mut self.timers, &mut self.local_events);
-                        self.node.borrow_mut().on_local_message(msg, &mut node_ctx);
+                        self.current_client = ctx.src().to().strip_prefix("client@").map(|c| c.to_string());
+                        self.call_node(ctx, None, |node, node_ctx| node.on_local_message(msg, node_ctx));
+                        self.current_client = None;
+                    }
+                    SysEvent::TimerFired { name, owner } => {
+                        self.log(ctx, LogKind::TimerFired { name: name.clone() });
+                        // timers owned by other nodes are forgotten by the system once they fire
+                        if owner == ctx.id {
+                            self.timers.remove(&(ctx.id.clone(), name.clone()));
+                        }
+                        self.notify_timer_observer(TimerEvent::Fired { time: ctx.time(), node: ctx.id.to(), name: name.clone() });
+                        self.call_node(ctx, None, |node, node_ctx| node.on_timer(name, node_ctx));
                     }
-                    SysEvent::TimerFired { name } => {
-                        println!("{:>9.3} {:>10} !-- {:<10}", ctx.time(), ctx.id.to(), name);
-                        self.timers.remove(&(ctx.id.clone(), name.clone()));
-                        let mut node_ctx = Context::new(ctx, &mut self.timers, &mut self.local_events);
-                        self.node.borrow_mut().on_timer(name, &mut node_ctx);
+                    SysEvent::PeriodicTimerFired { name, interval } => {
+                        self.log(ctx, LogKind::TimerFired { name: name.clone() });
+                        let event = SysEvent::PeriodicTimerFired { name: name.clone(), interval };
+                        let event_id = ctx.emit(event, ctx.id.clone(), interval * self.slowdown);
+                        self.timers.insert((ctx.id.clone(), name.clone()), event_id);
+                        self.notify_timer_observer(TimerEvent::Fired { time: ctx.time(), node: ctx.id.to(), name: name.clone() });
+                        self.call_node(ctx, None, |node, node_ctx| node.on_timer(name, node_ctx));
                     }
                     _ => ()
                 }
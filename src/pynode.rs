@@ -34,7 +34,7 @@ impl JsonMessage {
         }
     }
 
-    pub fn to<'de, T>(&'de self) -> Box<T> where T: ?Sized + Deserialize<'de> {
+    pub fn to<'de, T>(&'de self) -> Box<T> where T: Deserialize<'de> {
         Box::new(serde_json::from_str(&self.data).unwrap())
     }
 }
@@ -72,11 +72,15 @@ impl PyNodeFactory {
     }
 
     pub fn build(&self, node_id: &str, args: impl IntoPy<Py<PyTuple>>) -> PyNode {
-        let node = Python::with_gil(|py| -> PyObject {
-            self.node_class.call1(py, args).unwrap().to_object(py)
+        let (node_class, args, node) = Python::with_gil(|py| -> (PyObject, Py<PyTuple>, PyObject) {
+            let args = args.into_py(py);
+            let node = self.node_class.call1(py, args.as_ref(py)).unwrap().to_object(py);
+            (self.node_class.clone_ref(py), args, node)
         });
         PyNode {
             id: node_id.to_string(),
+            node_class,
+            args,
             node,
             msg_class: self.msg_class.clone(),
             ctx_class: self.ctx_class.clone(),
@@ -86,6 +90,9 @@ impl PyNodeFactory {
 
 pub struct PyNode {
     id: String,
+    // constructor and its arguments, used to build a fresh node on recovery
+    node_class: PyObject,
+    args: Py<PyTuple>,
     node: PyObject,
     msg_class: Rc<PyObject>,
     ctx_class: Rc<PyObject>,
@@ -151,6 +158,12 @@ impl Node<JsonMessage> for PyNode {
             PyNode::handle_node_actions(ctx, &py_ctx, py);
         });
     }
+
+    fn reset(&mut self) {
+        self.node = Python::with_gil(|py| -> PyObject {
+            self.node_class.call1(py, self.args.as_ref(py)).unwrap().to_object(py)
+        });
+    }
 }
 
 fn log_python_error(e: PyErr, py: Python) -> PyErr {
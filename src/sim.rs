@@ -1,20 +1,54 @@
 use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fmt::{Debug, Error, Formatter};
 use std::rc::Rc;
 
 use decorum::R64;
 use rand::prelude::*;
 use rand_pcg::Pcg64;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EventEntry<E: Debug> {
     id: u64,
     time: R64,
     src: ActorId,
     dest: ActorId,
     event: E,
+    cause: Option<u64>,
+    // Identity of the event which is stable across runs of the same schedule: the processing step
+    // of its cause (None for events added from outside) and its index among the events
+    // the cause scheduled for the same destination
+    cause_step: Option<u64>,
+    seq: u64,
+}
+
+impl<E: Debug> EventEntry<E> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn time(&self) -> f64 {
+        self.time.into_inner()
+    }
+
+    pub fn src(&self) -> &ActorId {
+        &self.src
+    }
+
+    pub fn dest(&self) -> &ActorId {
+        &self.dest
+    }
+
+    pub fn event(&self) -> &E {
+        &self.event
+    }
+
+    pub fn cause(&self) -> Option<u64> {
+        self.cause
+    }
 }
 
 impl<E: Debug> Eq for EventEntry<E> {}
@@ -25,6 +59,9 @@ impl<E: Debug> PartialEq for EventEntry<E> {
     }
 }
 
+// Events are processed in order of their time. Events with identical time are processed
+// in the order they were scheduled: ids are assigned from a monotonically increasing counter,
+// so the same seed and the same code always produce the same schedule.
 impl<E: Debug> Ord for EventEntry<E> {
     fn cmp(&self, other: &Self) -> Ordering {
         other.time.cmp(&self.time)
@@ -38,7 +75,19 @@ impl<E: Debug> PartialOrd for EventEntry<E> {
     }
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TraceEntry {
+    pub time: f64,
+    pub src: String,
+    pub dest: String,
+    pub event: String,
+    pub cause_step: Option<u64>,
+    pub seq: u64,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ActorId(String);
 
 impl std::fmt::Display for ActorId {
@@ -76,8 +125,10 @@ pub struct CtxEvent<E> {
 
 pub struct ActorContext<'a, E: Debug> {
     pub id: ActorId,
+    src: ActorId,
     time: f64,
     rand: &'a mut Pcg64,
+    replaying: bool,
     next_event_id: u64,
     events: Vec<CtxEvent<E>>,
     canceled_events: Vec<u64>,
@@ -88,6 +139,11 @@ impl<'a, E: Debug> ActorContext<'a, E> {
         self.time
     }
 
+    // Actor which emitted the event being handled
+    pub fn src(&self) -> &ActorId {
+        &self.src
+    }
+
     pub fn emit(&mut self, event: E, dest: ActorId, delay: f64) -> u64 {
         let entry = CtxEvent { event, dest, delay };
         self.events.push(entry);
@@ -99,98 +155,452 @@ impl<'a, E: Debug> ActorContext<'a, E> {
         self.rand.gen_range(0.0..1.0)
     }
 
+    pub fn rng(&mut self) -> &mut Pcg64 {
+        self.rand
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replaying
+    }
+
     pub fn cancel_event(&mut self, event_id: u64) {
         // println!("Canceled event: {}", event_id);
         self.canceled_events.push(event_id);
     }
 }
 
+pub type Adversary<E> = Box<dyn FnMut(&[E]) -> usize>;
+
 pub struct Simulation<E: Debug> {
+    seed: Option<u64>,
     clock: R64,
     actors: HashMap<ActorId, Rc<RefCell<dyn Actor<E>>>>,
     events: BinaryHeap<EventEntry<E>>,
     canceled_events: HashSet<u64>,
     undelivered_events: Vec<EventEntry<E>>,
+    // kept only once `record_discarded_events` is called
+    discarded_events: Option<Vec<EventEntry<E>>>,
     event_count: u64,
     rand: Pcg64,
+    history: Option<Vec<EventEntry<E>>>,
+    replay: Option<VecDeque<TraceEntry>>,
+    // events scheduled during replay by (src, dest, cause step, seq)
+    replay_index: HashMap<(String, String, Option<u64>, u64), EventEntry<E>>,
+    // last replayed event by (src, dest, cause step), used for duplicates
+    replayed_events: HashMap<(String, String, Option<u64>), E>,
+    root_seqs: HashMap<(ActorId, ActorId), u64>,
+    // first trace entry which could not be matched during replay
+    replay_divergence: Option<TraceEntry>,
+    adversary: Option<Adversary<E>>,
+    adversary_window: f64,
+    fair: bool,
+    processed_count: u64,
+    last_served: HashMap<ActorId, u64>,
 }
 
-impl<E: Debug> Simulation<E> {
+impl<E: Debug + Clone> Simulation<E> {
     pub fn new(seed: u64) -> Self {
+        let mut sim = Self::with_rng(Pcg64::seed_from_u64(seed));
+        sim.seed = Some(seed);
+        sim
+    }
+
+    pub fn with_rng(rand: Pcg64) -> Self {
         Self {
+            seed: None,
             clock: R64::from_inner(0.0),
             actors: HashMap::new(),
             events: BinaryHeap::new(),
             canceled_events: HashSet::new(),
             undelivered_events: Vec::new(),
+            discarded_events: None,
             event_count: 0,
-            rand: Pcg64::seed_from_u64(seed),
+            rand,
+            history: None,
+            replay: None,
+            replay_index: HashMap::new(),
+            replayed_events: HashMap::new(),
+            root_seqs: HashMap::new(),
+            replay_divergence: None,
+            adversary: None,
+            adversary_window: 0.,
+            fair: false,
+            processed_count: 0,
+            last_served: HashMap::new(),
         }
     }
 
+    pub fn from_trace(trace: Vec<TraceEntry>) -> Self {
+        let mut sim = Self::new(0);
+        sim.replay = Some(trace.into());
+        sim
+    }
+
+    // Copies the simulation state except for the actors, which are not owned by the snapshot
+    pub fn snapshot(&self) -> Self {
+        Self {
+            seed: self.seed,
+            clock: self.clock,
+            actors: HashMap::new(),
+            events: self.events.clone(),
+            canceled_events: self.canceled_events.clone(),
+            undelivered_events: self.undelivered_events.clone(),
+            discarded_events: self.discarded_events.clone(),
+            event_count: self.event_count,
+            rand: self.rand.clone(),
+            history: self.history.clone(),
+            replay: self.replay.clone(),
+            replay_index: self.replay_index.clone(),
+            replayed_events: self.replayed_events.clone(),
+            root_seqs: self.root_seqs.clone(),
+            replay_divergence: self.replay_divergence.clone(),
+            adversary: None,
+            adversary_window: self.adversary_window,
+            fair: self.fair,
+            processed_count: self.processed_count,
+            last_served: self.last_served.clone(),
+        }
+    }
+
+    // Like `snapshot`, but the copy gets its own RNG seeded from the next value of this one,
+    // so its random decisions are deterministic yet independent from the parent's
+    pub fn fork(&self) -> Self {
+        let mut sim = self.snapshot();
+        let seed = self.rand.clone().next_u64();
+        sim.seed = Some(seed);
+        sim.rand = Pcg64::seed_from_u64(seed);
+        sim
+    }
+
+    // Restores the state saved by `snapshot`, keeping the current actors and adversary
+    pub fn restore(&mut self, snapshot: &Self) {
+        let actors = std::mem::take(&mut self.actors);
+        let adversary = self.adversary.take();
+        *self = snapshot.snapshot();
+        self.actors = actors;
+        self.adversary = adversary;
+    }
+
+    pub fn replay_divergence(&self) -> Option<&TraceEntry> {
+        self.replay_divergence.as_ref()
+    }
+
+    pub fn record_trace(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(Vec::new());
+        }
+    }
+
+    pub fn trace(&self) -> Vec<TraceEntry> {
+        self.history().iter()
+            .map(|e| TraceEntry {
+                time: e.time.into_inner(),
+                src: e.src.to(),
+                dest: e.dest.to(),
+                event: format!("{:?}", e.event),
+                cause_step: e.cause_step,
+                seq: e.seq,
+            })
+            .collect()
+    }
+
+    pub fn is_recording_trace(&self) -> bool {
+        self.history.is_some()
+    }
+
+    pub fn history(&self) -> &[EventEntry<E>] {
+        self.history.as_deref().unwrap_or(&[])
+    }
+
+    // None if the simulation was created with an external RNG
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
     pub fn time(&self) -> f64 {
         self.clock.into_inner()
     }
 
+    pub fn rand(&mut self) -> f64 {
+        self.rand.gen_range(0.0..1.0)
+    }
+
+    pub fn processed_count(&self) -> u64 {
+        self.processed_count
+    }
+
     pub fn add_actor(&mut self, id: &str, actor: Rc<RefCell<dyn Actor<E>>>) {
         self.actors.insert(ActorId(id.to_string()), actor);
     }
 
+    pub fn remove_actor(&mut self, id: &str) {
+        self.actors.remove(&ActorId(id.to_string()));
+    }
+
     pub fn add_event(&mut self, event: E, src: ActorId, dest: ActorId, delay: f64) -> u64 {
+        let seq = self.root_seqs.entry((src.clone(), dest.clone())).or_insert(0);
+        *seq += 1;
+        let seq = *seq - 1;
+        self.add_caused_event(event, src, dest, delay, None, seq)
+    }
+
+    // `cause` is the id and processing step of the event during which this one was scheduled
+    fn add_caused_event(&mut self, event: E, src: ActorId, dest: ActorId, delay: f64, cause: Option<(u64, u64)>, seq: u64) -> u64 {
         let entry = EventEntry {
             id: self.event_count,
             time: self.clock + delay,
             src,
             dest,
             event,
+            cause: cause.map(|(id, _)| id),
+            cause_step: cause.map(|(_, step)| step),
+            seq,
         };
         let id = entry.id;
+        if self.replay.is_some() {
+            let key = (entry.src.to(), entry.dest.to(), entry.cause_step, seq);
+            self.replay_index.insert(key, entry.clone());
+        }
         self.events.push(entry);
         self.event_count += 1;
         id
     }
 
+    // Lets the adversary choose the next event among the eligible ones, i.e. events
+    // scheduled within `window` from the earliest pending event (0 means same time)
+    pub fn set_adversary(&mut self, f: Option<Adversary<E>>, window: f64) {
+        self.adversary = f;
+        self.adversary_window = window;
+    }
+
+    // Among events with the same time, process first the one whose destination
+    // was served least recently instead of the one scheduled first
+    pub fn set_fair_scheduling(&mut self, enabled: bool) {
+        self.fair = enabled;
+    }
+
     pub fn cancel_event(&mut self, event_id: u64) {
         self.canceled_events.insert(event_id);
     }
 
+    pub fn cancel_events<F: Fn(&EventEntry<E>) -> bool>(&mut self, f: F) {
+        for entry in self.events.iter() {
+            if f(entry) {
+                self.canceled_events.insert(entry.id);
+            }
+        }
+    }
+
+    pub fn pending_events(&self) -> Vec<&EventEntry<E>> {
+        let mut events: Vec<&EventEntry<E>> = self.events.iter()
+            .filter(|e| !self.canceled_events.contains(&e.id))
+            .collect();
+        events.sort_by(|a, b| b.cmp(a));
+        events
+    }
+
     pub fn step(&mut self) -> bool {
-        if let Some(e) = self.events.pop() {
+        self.step_detailed().is_some()
+    }
+
+    pub fn step_detailed(&mut self) -> Option<EventEntry<E>> {
+        if self.replay.is_some() {
+            return self.replay_step();
+        }
+        if self.adversary.is_some() || self.fair {
+            return self.choice_step();
+        }
+        while let Some(e) = self.events.pop() {
+            if !self.canceled_events.remove(&e.id) {
+                let processed = e.clone();
+                self.process(e);
+                return Some(processed);
+            }
+        }
+        None
+    }
+
+    // Processes the earliest pending event for which `f` returns true, leaving the events
+    // before it queued. Ignores the adversary and fair scheduling.
+    pub fn step_matching<F: Fn(&EventEntry<E>) -> bool>(&mut self, f: F) -> Option<EventEntry<E>> {
+        let mut skipped = Vec::new();
+        let mut found = None;
+        while let Some(e) = self.events.pop() {
+            if self.canceled_events.remove(&e.id) {
+                continue;
+            }
+            if f(&e) {
+                found = Some(e);
+                break;
+            }
+            skipped.push(e);
+        }
+        self.events.extend(skipped);
+        let e = found?;
+        let processed = e.clone();
+        self.process(e);
+        Some(processed)
+    }
+
+    fn choice_step(&mut self) -> Option<EventEntry<E>> {
+        let window = if self.adversary.is_some() { self.adversary_window } else { 0. };
+        let frontier = self.next_event_time()? + window;
+        let mut eligible = Vec::new();
+        while let Some(e) = self.events.pop() {
+            if e.time > frontier {
+                self.events.push(e);
+                break;
+            }
             if !self.canceled_events.remove(&e.id) {
-                // println!("{} {}->{} {:?}", e.time, e.src, e.dest, e.event);
-                self.clock = e.time;
-                let actor = self.actors.get(&e.dest);
-                let mut ctx = ActorContext {
-                    id: e.dest.clone(),
-                    time: self.clock.into_inner(),
-                    rand: &mut self.rand,
-                    next_event_id: self.event_count,
-                    events: Vec::new(),
-                    canceled_events: Vec::new(),
-                };
-                match actor {
-                    Some(actor) => {
-                        if actor.borrow().is_active() {
-                            actor.borrow_mut().on(e.event, &mut ctx);
-                            let canceled = ctx.canceled_events.clone();
-                            for ctx_e in ctx.events {
-                                self.add_event(ctx_e.event, e.dest.clone(), ctx_e.dest, ctx_e.delay);
-                            };
-                            for event_id in canceled {
-                                self.cancel_event(event_id);
-                            };
-                        } else {
-                            //println!("Discarded event for inactive actor {}", e.dest);
-                        }
+                eligible.push(e);
+            }
+        }
+        let choice = if eligible.len() <= 1 {
+            0
+        } else if let Some(adversary) = self.adversary.as_mut() {
+            let events: Vec<E> = eligible.iter().map(|e| e.event.clone()).collect();
+            adversary(&events)
+        } else {
+            // eligible events are popped in scheduling order, which breaks the remaining ties
+            let last_served = &self.last_served;
+            eligible.iter().enumerate()
+                .min_by_key(|(_, e)| last_served.get(&e.dest).copied())
+                .map(|(i, _)| i)
+                .unwrap()
+        };
+        assert!(choice < eligible.len(), "Adversary chose event {} out of {}", choice, eligible.len());
+        let e = eligible.swap_remove(choice);
+        self.events.extend(eligible);
+        let processed = e.clone();
+        self.process(e);
+        Some(processed)
+    }
+
+    fn replay_step(&mut self) -> Option<EventEntry<E>> {
+        let entry = match self.replay.as_mut().unwrap().pop_front() {
+            Some(entry) => entry,
+            None => {
+                // the rest were not processed in the recorded run, e.g. messages it dropped
+                self.events.clear();
+                self.canceled_events.clear();
+                self.replay_index.clear();
+                return None;
+            }
+        };
+        let key = (entry.src.clone(), entry.dest.clone(), entry.cause_step, entry.seq);
+        let found = self.replay_index.remove(&key).filter(|e| !self.canceled_events.contains(&e.id));
+        let mut e = match found {
+            Some(e) => {
+                // the queued copy is skipped when it reaches the top of the queue
+                self.canceled_events.insert(e.id);
+                while let Some(id) = self.events.peek().map(|e| e.id) {
+                    if !self.canceled_events.remove(&id) {
+                        break;
                     }
-                    _ => {
-                        self.undelivered_events.push(e);
+                    self.events.pop();
+                }
+                e
+            }
+            // copies of events which were duplicated by the network in the recorded run,
+            // they may be delivered before or after the original
+            None => match self.replayed_events.get(&(key.0.clone(), key.1.clone(), key.2))
+                .or_else(|| self.replay_index.get(&(key.0.clone(), key.1.clone(), key.2, 0)).map(|e| &e.event))
+                .cloned()
+            {
+                Some(event) => {
+                    self.event_count += 1;
+                    EventEntry {
+                        id: self.event_count - 1,
+                        time: self.clock,
+                        src: ActorId(entry.src.clone()),
+                        dest: ActorId(entry.dest.clone()),
+                        event,
+                        cause: None,
+                        cause_step: entry.cause_step,
+                        seq: entry.seq,
                     }
                 }
+                None => {
+                    self.replay_divergence = Some(entry);
+                    self.replay = Some(VecDeque::new());
+                    return None;
+                }
+            }
+        };
+        e.time = R64::from_inner(entry.time);
+        self.replayed_events.insert((entry.src, entry.dest, entry.cause_step), e.event.clone());
+        let processed = e.clone();
+        self.process(e);
+        Some(processed)
+    }
+
+    fn process(&mut self, e: EventEntry<E>) {
+        // println!("{} {}->{} {:?}", e.time, e.src, e.dest, e.event);
+        // events picked by the adversary within its window may be scheduled earlier than the clock
+        self.clock = self.clock.max(e.time);
+        self.processed_count += 1;
+        if self.fair {
+            self.last_served.insert(e.dest.clone(), self.processed_count);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.push(e.clone());
+        }
+        let actor = self.actors.get(&e.dest);
+        let mut ctx = ActorContext {
+            id: e.dest.clone(),
+            src: e.src.clone(),
+            time: self.clock.into_inner(),
+            rand: &mut self.rand,
+            replaying: self.replay.is_some(),
+            next_event_id: self.event_count,
+            events: Vec::new(),
+            canceled_events: Vec::new(),
+        };
+        match actor {
+            Some(actor) => {
+                if actor.borrow().is_active() {
+                    actor.borrow_mut().on(e.event, &mut ctx);
+                    let canceled = ctx.canceled_events.clone();
+                    let step = self.processed_count - 1;
+                    let mut seqs: HashMap<ActorId, u64> = HashMap::new();
+                    for ctx_e in ctx.events {
+                        let seq = seqs.entry(ctx_e.dest.clone()).or_insert(0);
+                        *seq += 1;
+                        let seq = *seq - 1;
+                        self.add_caused_event(ctx_e.event, e.dest.clone(), ctx_e.dest, ctx_e.delay, Some((e.id, step)), seq);
+                    };
+                    for event_id in canceled {
+                        self.cancel_event(event_id);
+                    };
+                } else {
+                    if let Some(discarded) = self.discarded_events.as_mut() {
+                        discarded.push(e);
+                    }
+                }
+            }
+            _ => {
+                self.undelivered_events.push(e);
             }
-            true
-        } else {
-            false
+        }
+    }
+
+    pub fn next_event_time(&mut self) -> Option<f64> {
+        if let Some(replay) = &self.replay {
+            return replay.front().map(|e| e.time);
+        }
+        while let Some((id, time)) = self.events.peek().map(|e| (e.id, e.time)) {
+            if self.canceled_events.remove(&id) {
+                self.events.pop();
+            } else {
+                return Some(time.into_inner());
+            }
+        }
+        None
+    }
+
+    pub fn advance_clock(&mut self, time: f64) {
+        if self.clock < time {
+            self.clock = R64::from_inner(time);
         }
     }
 
@@ -208,7 +618,7 @@ impl<E: Debug> Simulation<E> {
         while self.step() {
             let mut has_matching_events = false;
             for event in self.events.iter() {
-                has_matching_events = has_matching_events | f(&event.event);
+                has_matching_events |= f(&event.event);
             }
             if !has_matching_events {
                 return
@@ -216,6 +626,27 @@ impl<E: Debug> Simulation<E> {
         }
     }
 
+    pub fn undelivered_events(&self) -> &[EventEntry<E>] {
+        &self.undelivered_events
+    }
+
+    pub fn record_discarded_events(&mut self) {
+        if self.discarded_events.is_none() {
+            self.discarded_events = Some(Vec::new());
+        }
+    }
+
+    // Events which were not processed because their destination actor was inactive
+    pub fn discarded_events(&self) -> Option<&[EventEntry<E>]> {
+        self.discarded_events.as_deref()
+    }
+
+    pub fn clear_discarded_events(&mut self) {
+        if let Some(discarded) = self.discarded_events.as_mut() {
+            discarded.clear();
+        }
+    }
+
     pub fn read_undelivered_events(&mut self) -> Vec<EventEntry<E>> {
         self.undelivered_events.drain(..).collect()
     }
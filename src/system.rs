@@ -1,15 +1,26 @@
-use std::cell::RefCell;
+use std::any::type_name;
+use std::cell::{Ref, RefCell};
+use std::hash::Hash;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::rc::Rc;
 
 use rand::prelude::*;
+use rand_pcg::Pcg64;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde_json::json;
 
 use crate::net::*;
 use crate::node::*;
 use crate::sim::*;
 
+#[cfg(feature = "serde")]
+pub const TRACE_SCHEMA_VERSION: u32 = 1;
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(tag = "type"))]
 pub enum SysEvent<M: Debug + Clone> {
     MessageSend {
         msg: M,
@@ -30,199 +41,2226 @@ pub enum SysEvent<M: Debug + Clone> {
     },
     TimerFired {
         name: String,
+        // node which set the timer and tracks it, may differ from the node where it fires
+        owner: ActorId,
+    },
+    PeriodicTimerFired {
+        name: String,
+        interval: f64,
+    },
+    Control {
+        action: ControlAction,
     },
+    MessageAcked {
+        src: ActorId,
+        dest: ActorId,
+    },
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ControlAction {
+    CrashNode { node: String },
+    RecoverNode { node: String },
+    LinkDown { from: String, to: String },
+    LinkUp { from: String, to: String },
+}
+
+#[derive(Default)]
+struct Control {
+    actions: Vec<ControlAction>,
+}
+
+impl<M: Debug + Clone> Actor<SysEvent<M>> for Control {
+    fn on(&mut self, event: SysEvent<M>, _ctx: &mut ActorContext<SysEvent<M>>) {
+        if let SysEvent::Control { action } = event {
+            self.actions.push(action);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TooManyStepsError {
+    pub max_steps: u64,
+    pub time: f64,
+    pub pending_events: usize,
+}
+
+impl std::fmt::Display for TooManyStepsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "step budget of {} exhausted at time {:.3} with {} pending events",
+               self.max_steps, self.time, self.pending_events)
+    }
+}
+
+impl std::error::Error for TooManyStepsError {}
+
+#[derive(Debug, Clone)]
+pub struct UnknownNodeError {
+    pub node_id: String,
+    pub valid_ids: Vec<String>,
+}
+
+impl std::fmt::Display for UnknownNodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown node id '{}', valid ids are: {}", self.node_id, self.valid_ids.join(", "))
+    }
+}
+
+impl std::error::Error for UnknownNodeError {}
+
+#[derive(Debug, Clone)]
+pub struct NoAliveNodesError;
+
+impl std::fmt::Display for NoAliveNodesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no alive nodes to choose from")
+    }
+}
+
+impl std::error::Error for NoAliveNodesError {}
+
+pub type Invariant<M> = Box<dyn Fn(&System<M>) -> Result<(), String>>;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(tag = "type"))]
+pub enum LogKind {
+    Seed { seed: u64 },
+    NodeCrashed,
+    NodeRecovered,
+    SendFromCrashedNode { dest: String, msg: String },
+    TimerDelayClamped { name: String, delay: f64, min: f64 },
+    MessageSent { dest: String, msg: String },
+    AnycastUnreachable { group: Vec<String>, msg: String },
+    MessageReceived { src: String, msg: String },
+    ReplayDiverged { src: String, dest: String, event: String },
+    Sent { dest: String, msg: String },
+    Received { src: String, msg: String },
+    LocalSent { dest: String, msg: String },
+    LocalReceived { msg: String },
+    TimerFired { name: String },
+    MessageDropped { dest: String, msg: String, reason: String },
+    MessageDiscarded { msg: String },
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LogRecord {
+    pub time: f64,
+    pub node: Option<String>,
+    pub kind: LogKind,
+}
+
+impl std::fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let node = self.node.as_deref().unwrap_or("");
+        match &self.kind {
+            LogKind::Seed { seed } => write!(f, "Seed: {}", seed),
+            LogKind::NodeCrashed => write!(f, "{:>9.3} {:>10} CRASHED!", self.time, node),
+            LogKind::NodeRecovered => write!(f, "{:>9.3} {:>10} RECOVERED", self.time, node),
+            LogKind::TimerDelayClamped { name, delay, min } => {
+                write!(f, "{:>9.3} {:>10} timer {} delay {} clamped to {}", self.time, node, name, delay, min)
+            }
+            LogKind::MessageSent { dest, msg } => {
+                write!(f, "{:>9.3} {:>10} --> {:<10} {} <-- traced", self.time, node, dest, msg)
+            }
+            LogKind::MessageReceived { src, msg } => {
+                write!(f, "{:>9.3} {:>10} <-- {:<10} {} <-- traced", self.time, node, src, msg)
+            }
+            LogKind::AnycastUnreachable { group, msg } => {
+                write!(f, "{:>9.3} {:>10} --x {:<10} {} <-- no reachable destination", self.time, node, group.join(","), msg)
+            }
+            LogKind::ReplayDiverged { src, dest, event } => {
+                write!(f, "{:>9.3} !!! Replay diverged from trace: {} -> {} {}", self.time, src, dest, event)
+            }
+            LogKind::SendFromCrashedNode { dest, msg } => {
+                write!(f, "{:>9.3} {:>10} --x {:<10} {} <-- sender is crashed", self.time, node, dest, msg)
+            }
+            LogKind::Sent { dest, msg } => write!(f, "{:>9.3} {:>10} --> {:<10} {}", self.time, node, dest, msg),
+            LogKind::Received { src, msg } => write!(f, "{:>9.3} {:>10} <-- {:<10} {}", self.time, node, src, msg),
+            LogKind::LocalSent { dest, msg } => write!(f, "{:>9.3} {:>10} >>> {:<10} {}", self.time, node, dest, msg),
+            LogKind::LocalReceived { msg } => write!(f, "{:>9.3} {:>10} <<< {:<10} {}", self.time, node, "local", msg),
+            LogKind::TimerFired { name } => write!(f, "{:>9.3} {:>10} !-- {:<10}", self.time, node, name),
+            LogKind::MessageDropped { dest, msg, reason } => {
+                write!(f, "{:>9} {:>10} --x {:<10} {} <-- {}", "!!!", node, dest, msg, reason)
+            }
+            LogKind::MessageDiscarded { msg } => write!(f, "!!! Discarded message from crashed node {}", msg),
+        }
+    }
+}
+
+pub type Logger = Box<dyn FnMut(&LogRecord)>;
+pub type ProgressFn = Box<dyn FnMut(u64, f64)>;
+
+#[derive(Debug, Clone)]
+pub enum UndeliveryReason {
+    // the network dropped the message, e.g. due to a fault or a disabled link
+    Dropped(String),
+    DestinationCrashed,
+    UnknownDestination,
+}
+
+#[derive(Debug, Clone)]
+pub struct UndeliveredEvent<M: Debug + Clone> {
+    pub time: f64,
+    pub src: ActorId,
+    pub dest: ActorId,
+    pub event: SysEvent<M>,
+    pub reason: UndeliveryReason,
+}
+
+/// Event processed by `System::step_detailed`.
+#[derive(Debug, Clone)]
+pub struct ProcessedEvent<M: Debug + Clone> {
+    pub id: u64,
+    pub time: f64,
+    pub src: ActorId,
+    pub dest: ActorId,
+    pub event: SysEvent<M>,
+}
+
+/// Event handled by a node in a `CausalGraph`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CausalEvent {
+    pub id: u64,
+    pub time: f64,
+    pub node: String,
+    pub event: String,
+}
+
+/// Happens-before graph built by `System::causal_graph`. Edges connect consecutive events
+/// of the same node and the event during which a message was sent to the event of its receipt.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CausalGraph {
+    pub events: Vec<CausalEvent>,
+    pub edges: Vec<(u64, u64)>,
+}
+
+impl CausalGraph {
+    /// Returns true if there is a path of edges from event `a` to event `b`.
+    pub fn happens_before(&self, a: u64, b: u64) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![a];
+        while let Some(id) = stack.pop() {
+            for (from, to) in &self.edges {
+                if *from == id && visited.insert(*to) {
+                    if *to == b {
+                        return true;
+                    }
+                    stack.push(*to);
+                }
+            }
+        }
+        false
+    }
+
+    pub fn concurrent(&self, a: u64, b: u64) -> bool {
+        a != b && !self.happens_before(a, b) && !self.happens_before(b, a)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Point-in-time copy of a system created by `System::snapshot`.
+pub struct Snapshot<M: Debug + Clone> {
+    sim: Simulation<SysEvent<M>>,
+    net: Network<M>,
+    nodes: HashMap<String, NodeSnapshot<M>>,
+    node_impls: HashMap<String, Rc<RefCell<dyn Node<M>>>>,
+    node_ids: Vec<String>,
+    crashed_nodes: HashSet<String>,
+    crash_history: Vec<(f64, String, bool)>,
+    replies: HashSet<u64>,
+    delivered: Option<Vec<M>>,
+    clients: HashMap<String, Vec<M>>,
 }
 
 pub struct System<M: Debug + Clone> {
     sim: Simulation<SysEvent<M>>,
-    net: Rc<RefCell<Network>>,
+    net: Rc<RefCell<Network<M>>>,
+    control: Rc<RefCell<Control>>,
     nodes: HashMap<String, Rc<RefCell<NodeActor<M>>>>,
+    node_impls: HashMap<String, Rc<RefCell<dyn Node<M>>>>,
     node_ids: Vec<String>,
     crashed_nodes: HashSet<String>,
+    invariants: Vec<(String, Invariant<M>)>,
+    loopback_mode: LoopbackMode,
+    min_timer_delay: f64,
+    logger: Rc<RefCell<Logger>>,
+    timer_observer: Option<Rc<RefCell<TimerObserver>>>,
+    // (time, node, crashed) for each crash and recovery
+    crash_history: Vec<(f64, String, bool)>,
+    replies: Rc<RefCell<HashSet<u64>>>,
+    progress: Option<(u64, ProgressFn)>,
+    delivered: Option<Vec<M>>,
+    members: Rc<RefCell<Vec<String>>>,
+    time_limit: Option<f64>,
+    time_limit_reached: bool,
+    // client -> node it talks to, outputs for clients are kept in `clients`
+    client_nodes: HashMap<String, String>,
+    clients: ClientOutputs<M>,
+    // initial timer of each node, also scheduled when the node recovers
+    init_timers: HashMap<String, Option<String>>,
+    trace_filter: Option<FilterFn<M>>,
+}
+
+impl<M: Debug + Clone + 'static> Default for System<M> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<M: Debug + Clone + 'static> System<M> {
     pub fn new() -> Self {
         let seed: u64 = thread_rng().gen_range(1..1_000_000);
-        println!("Seed: {}", seed);
-        System::with_seed(seed)
+        let system = System::with_seed(seed);
+        system.print_seed();
+        system
+    }
+
+    pub fn builder() -> SystemBuilder<M> {
+        SystemBuilder::new()
+    }
+
+    /// Seed of the simulation RNG, None if the system was created with `with_rng`.
+    pub fn seed(&self) -> Option<u64> {
+        self.sim.seed()
+    }
+
+    pub fn time(&self) -> f64 {
+        self.sim.time()
+    }
+
+    pub fn print_seed(&self) {
+        if let Some(seed) = self.seed() {
+            self.log(None, LogKind::Seed { seed });
+        }
+    }
+
+    /// Replaces the default logger, which prints records to stdout,
+    /// e.g. to filter them or to capture them in tests.
+    pub fn set_logger(&mut self, logger: Logger) {
+        // the handle is shared with node actors, so only its contents are replaced
+        *self.logger.borrow_mut() = logger;
+    }
+
+    fn log(&self, node: Option<&str>, kind: LogKind) {
+        let record = LogRecord { time: self.sim.time(), node: node.map(|n| n.to_string()), kind };
+        (self.logger.borrow_mut())(&record);
     }
 
     pub fn with_seed(seed: u64) -> Self {
-        let mut sim = Simulation::<SysEvent<M>>::new(seed);
+        System::with_sim(Simulation::new(seed))
+    }
+
+    /// Creates a system driven by an externally constructed RNG,
+    /// which is shared by the simulation and the network. `seed` returns None for such systems.
+    pub fn with_rng(rng: Pcg64) -> Self {
+        System::with_sim(Simulation::with_rng(rng))
+    }
+
+    /// Creates a system which processes events in the order given by a trace recorded
+    /// with `record_trace`. Network drops and duplicates are taken from the trace, not the RNG.
+    /// Events are matched to the trace by their causes rather than by content, so the trace
+    /// must be recorded from the start of the run. Events still pending once the trace
+    /// is exhausted, e.g. messages dropped in the recorded run, are discarded.
+    pub fn replay(trace: Vec<TraceEntry>) -> Self {
+        System::with_sim(Simulation::from_trace(trace))
+    }
+
+    fn with_sim(mut sim: Simulation<SysEvent<M>>) -> Self {
+        let logger: Rc<RefCell<Logger>> = Rc::new(RefCell::new(Box::new(|record| println!("{}", record))));
         let net = Rc::new(RefCell::new(Network::new()));
+        net.borrow_mut().set_logger(Some(logger.clone()));
         sim.add_actor("net", net.clone());
+        let control = Rc::new(RefCell::new(Control::default()));
+        sim.add_actor("control", control.clone());
         Self {
             sim,
             net,
+            control,
             nodes: HashMap::new(),
+            node_impls: HashMap::new(),
             node_ids: Vec::new(),
             crashed_nodes: HashSet::new(),
+            invariants: Vec::new(),
+            loopback_mode: LoopbackMode::Direct,
+            min_timer_delay: 0.,
+            logger,
+            timer_observer: None,
+            crash_history: Vec::new(),
+            replies: Rc::new(RefCell::new(HashSet::new())),
+            progress: None,
+            delivered: None,
+            members: Rc::new(RefCell::new(Vec::new())),
+            time_limit: None,
+            time_limit_reached: false,
+            client_nodes: HashMap::new(),
+            clients: Rc::new(RefCell::new(HashMap::new())),
+            init_timers: HashMap::new(),
+            trace_filter: None,
         }
     }
 
     pub fn add_node(&mut self, node: Rc<RefCell<dyn Node<M>>>) {
+        self.add_node_with_init(node, Some("init"));
+    }
+
+    /// Adds the node and schedules its initial timer with the given name, or none if `init` is None.
+    /// The same timer is scheduled when the node recovers from a crash.
+    pub fn add_node_with_init(&mut self, node: Rc<RefCell<dyn Node<M>>>, init: Option<&str>) {
         let id = node.borrow().id().to_string();
-        let actor = Rc::new(RefCell::new(NodeActor::new(node)));
+        let actor = self.new_node_actor(node.clone());
         self.sim.add_actor(&id, actor.clone());
         self.nodes.insert(id.clone(), actor);
-        self.node_ids.push(id.clone());
-        self.add_timer(&id, "init");
+        self.node_impls.insert(id.clone(), node);
+        if !self.node_ids.contains(&id) {
+            self.node_ids.push(id.clone());
+        }
+        self.crashed_nodes.remove(&id);
+        self.update_members();
+        self.net.borrow_mut().node_recovered(&id);
+        self.net.borrow_mut().connect_node(&id);
+        self.init_timers.insert(id.clone(), init.map(|name| name.to_string()));
+        // timers are scheduled relative to the current time, so nodes can be added mid-run
+        if let Some(name) = init {
+            self.add_timer(&id, name);
+        }
+    }
+
+    fn new_node_actor(&self, node: Rc<RefCell<dyn Node<M>>>) -> Rc<RefCell<NodeActor<M>>> {
+        let actor = Rc::new(RefCell::new(NodeActor::new(node)));
+        actor.borrow_mut().set_loopback_mode(self.loopback_mode);
+        actor.borrow_mut().set_min_timer_delay(self.min_timer_delay);
+        actor.borrow_mut().set_timer_observer(self.timer_observer.clone());
+        actor.borrow_mut().set_reply_registry(Some(self.replies.clone()));
+        actor.borrow_mut().set_members(Some(self.members.clone()));
+        actor.borrow_mut().set_client_registry(Some(self.clients.clone()));
+        actor.borrow_mut().set_logger(Some(self.logger.clone()));
+        actor
+    }
+
+    // Alive nodes as seen by `Context::members`
+    fn update_members(&self) {
+        *self.members.borrow_mut() = self.node_ids.iter()
+            .filter(|id| !self.crashed_nodes.contains(*id))
+            .cloned()
+            .collect();
+    }
+
+    /// Removes the node from the system. Its pending timers, messages in flight towards it
+    /// and messages it sent that have not reached the network yet are dropped,
+    /// messages sent to it later are reported as undelivered.
+    pub fn remove_node(&mut self, node_id: &str) {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+        let node = ActorId::from(node_id);
+        self.sim.cancel_events(|e| *e.dest() == node || *e.src() == node);
+        self.sim.remove_actor(node_id);
+        self.nodes.remove(node_id);
+        self.node_impls.remove(node_id);
+        self.node_ids.retain(|id| id != node_id);
+        self.crashed_nodes.remove(node_id);
+        self.update_members();
+        self.net.borrow_mut().remove_node(node_id);
     }
 
     pub fn add_timer(&mut self, node_id: &str, name: &str) {
-        self.sim.add_event(
-            SysEvent::TimerFired { name: name.to_string() },
-            ActorId::from(node_id),
-            ActorId::from(node_id),
-            0.0,
-        );
+        let event = SysEvent::TimerFired { name: name.to_string(), owner: ActorId::from(node_id) };
+        self.schedule_timer(node_id, name, event, 0.0);
     }
 
-    pub fn get_node_ids(&self) -> Vec<String> {
-        self.node_ids.clone()
+    /// Sets a timer which fires at the node every `interval`, starting `interval` from now.
+    /// Panics if `interval` is not positive.
+    pub fn add_periodic_timer(&mut self, node_id: &str, name: &str, interval: f64) {
+        assert!(interval > 0., "Timer interval must be positive, got {}", interval);
+        let interval = self.clamp_timer_delay(node_id, name, interval);
+        let event = SysEvent::PeriodicTimerFired { name: name.to_string(), interval };
+        self.schedule_timer(node_id, name, event, interval);
     }
 
-    pub fn crash_node(&mut self, node_id: &str) {
-        println!("{:>9.3} {:>10} CRASHED!", self.sim.time(), node_id);
-        self.crashed_nodes.insert(node_id.to_string());
-        let mut node = self.nodes.get(node_id).unwrap().borrow_mut();
-        node.crash();
-        self.net.borrow_mut().node_crashed(node_id);
+    fn clamp_timer_delay(&self, node_id: &str, name: &str, delay: f64) -> f64 {
+        if delay < self.min_timer_delay {
+            self.log(Some(node_id), LogKind::TimerDelayClamped { name: name.to_string(), delay, min: self.min_timer_delay });
+            self.min_timer_delay
+        } else {
+            delay
+        }
     }
 
-    pub fn node_is_crashed(&self, node_id: &str) -> bool {
-        self.crashed_nodes.contains(node_id)
+    fn schedule_timer(&mut self, node_id: &str, name: &str, event: SysEvent<M>, delay: f64) {
+        let node = self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e)).clone();
+        let event_id = self.sim.add_event(event, ActorId::from(node_id), ActorId::from(node_id), delay);
+        let old_event_id = node.borrow_mut().register_timer(name, event_id);
+        self.replace_timer(old_event_id, node_id, name);
+        self.notify_timer_observer(TimerEvent::Set {
+            time: self.sim.time(), node: node_id.to_string(), name: name.to_string(), delay
+        });
     }
 
-    pub fn node_count(&self) -> u32 {
-        self.nodes.len() as u32
+    /// Schedules a timer which fires at `target` after `delay`. The timer is tracked
+    /// by `owner`, so it can be canceled with `cancel_timer_for` on behalf of the owner.
+    pub fn add_timer_for(&mut self, owner: &str, target: &str, name: &str, delay: f64) {
+        self.node_actor(target).unwrap_or_else(|e| panic!("{}", e));
+        let node = self.node_actor(owner).unwrap_or_else(|e| panic!("{}", e)).clone();
+        let delay = self.clamp_timer_delay(target, name, delay);
+        let event = SysEvent::TimerFired { name: name.to_string(), owner: ActorId::from(owner) };
+        let event_id = self.sim.add_event(event, ActorId::from(owner), ActorId::from(target), delay);
+        let old_event_id = node.borrow_mut().register_timer_for(target, name, event_id);
+        self.replace_timer(old_event_id, target, name);
+        self.notify_timer_observer(TimerEvent::Set {
+            time: self.sim.time(), node: target.to_string(), name: name.to_string(), delay
+        });
     }
 
-    pub fn set_delay(&mut self, delay: f64) {
-        self.net.borrow_mut().set_delay(delay);
+    // Cancels the timer replaced by a newly set one with the same name
+    fn replace_timer(&mut self, old_event_id: Option<u64>, target: &str, name: &str) {
+        if let Some(old_event_id) = old_event_id {
+            self.sim.cancel_event(old_event_id);
+            self.notify_timer_observer(TimerEvent::Cancelled {
+                time: self.sim.time(), node: target.to_string(), name: name.to_string()
+            });
+        }
     }
 
-    pub fn set_delays(&mut self, min_delay: f64, max_delay: f64) {
-        self.net.borrow_mut().set_delays(min_delay, max_delay);
+    pub fn cancel_timer_for(&mut self, owner: &str, target: &str, name: &str) {
+        let event_id = self.nodes.get(owner).and_then(|node| node.borrow_mut().unregister_timer_for(target, name));
+        if let Some(event_id) = event_id {
+            self.sim.cancel_event(event_id);
+            self.notify_timer_observer(TimerEvent::Cancelled {
+                time: self.sim.time(), node: target.to_string(), name: name.to_string()
+            });
+        }
     }
 
-    pub fn set_drop_rate(&mut self, drop_rate: f64) {
-        self.net.borrow_mut().set_drop_rate(drop_rate);
+    pub fn cancel_timer(&mut self, node_id: &str, name: &str) {
+        let event_id = self.nodes.get(node_id).and_then(|node| node.borrow_mut().unregister_timer(name));
+        if let Some(event_id) = event_id {
+            self.sim.cancel_event(event_id);
+            self.notify_timer_observer(TimerEvent::Cancelled {
+                time: self.sim.time(), node: node_id.to_string(), name: name.to_string()
+            });
+        }
     }
 
-    pub fn set_dupl_rate(&mut self, dupl_rate: f64) {
-        self.net.borrow_mut().set_dupl_rate(dupl_rate);
+    /// Cancels all timers set by or for the node whose name starts with `prefix`,
+    /// e.g. all `retransmit@<peer>` timers with the prefix `retransmit@`.
+    pub fn cancel_timers_with_prefix(&mut self, node_id: &str, prefix: &str) {
+        let timers = self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e))
+            .borrow_mut().unregister_timers_with_prefix(prefix);
+        for ((target, name), event_id) in timers {
+            self.sim.cancel_event(event_id);
+            self.notify_timer_observer(TimerEvent::Cancelled { time: self.sim.time(), node: target.to(), name });
+        }
     }
 
-    pub fn drop_incoming(&mut self, node_id: &str) {
-        self.net.borrow_mut().drop_incoming(node_id);
+    /// Calls `f` whenever a timer is set, fires or is cancelled, either by a node or via the system.
+    pub fn set_timer_observer(&mut self, f: TimerObserver) {
+        self.timer_observer = Some(Rc::new(RefCell::new(f)));
+        for actor in self.nodes.values() {
+            actor.borrow_mut().set_timer_observer(self.timer_observer.clone());
+        }
     }
 
-    pub fn pass_incoming(&mut self, node_id: &str) {
-        self.net.borrow_mut().pass_incoming(node_id);
+    pub fn clear_timer_observer(&mut self) {
+        self.timer_observer = None;
+        for actor in self.nodes.values() {
+            actor.borrow_mut().set_timer_observer(None);
+        }
     }
 
-    pub fn drop_outgoing(&mut self, node_id: &str) {
-        self.net.borrow_mut().drop_outgoing(node_id);
+    fn notify_timer_observer(&self, event: TimerEvent) {
+        if let Some(observer) = &self.timer_observer {
+            (observer.borrow_mut())(&event);
+        }
     }
 
-    pub fn pass_outgoing(&mut self, node_id: &str) {
-        self.net.borrow_mut().pass_outgoing(node_id);
+    pub fn get_pending_timers(&self, node_id: &str) -> Vec<(String, f64)> {
+        let dest = ActorId::from(node_id);
+        let mut timers = Vec::new();
+        for entry in self.sim.pending_events() {
+            if *entry.dest() != dest {
+                continue;
+            }
+            match entry.event() {
+                SysEvent::TimerFired { name, .. } | SysEvent::PeriodicTimerFired { name, .. } => {
+                    timers.push((name.clone(), entry.time()));
+                }
+                _ => ()
+            }
+        }
+        timers
     }
 
-    pub fn disconnect_node(&mut self, node_id: &str) {
-        self.net.borrow_mut().disconnect_node(node_id);
+    pub fn get_node<T: Node<M> + 'static>(&self, node_id: &str) -> Ref<'_, T> {
+        self.try_get_node(node_id).unwrap_or_else(|e| panic!("{}", e))
     }
 
-    pub fn connect_node(&mut self, node_id: &str) {
-        self.net.borrow_mut().connect_node(node_id);
+    pub fn try_get_node<T: Node<M> + 'static>(&self, node_id: &str) -> Result<Ref<'_, T>, UnknownNodeError> {
+        let node = self.node_impls.get(node_id)
+            .ok_or_else(|| self.unknown_node(node_id))?
+            .borrow();
+        Ok(Ref::map(node, |n| {
+            n.as_any().downcast_ref::<T>().unwrap_or_else(|| {
+                panic!("Node {} is not of type {}", node_id, type_name::<T>())
+            })
+        }))
     }
 
-    pub fn disable_link(&mut self, from: &str, to: &str) {
-        self.net.borrow_mut().disable_link(from, to);
+    fn node_actor(&self, node_id: &str) -> Result<&Rc<RefCell<NodeActor<M>>>, UnknownNodeError> {
+        self.nodes.get(node_id).ok_or_else(|| self.unknown_node(node_id))
     }
 
-    pub fn enable_link(&mut self, from: &str, to: &str) {
-        self.net.borrow_mut().enable_link(from, to);
+    fn unknown_node(&self, node_id: &str) -> UnknownNodeError {
+        UnknownNodeError {
+            node_id: node_id.to_string(),
+            valid_ids: self.node_ids.clone(),
+        }
     }
 
+    pub fn get_node_ids(&self) -> Vec<String> {
+        self.node_ids.clone()
+    }
 
-    pub fn enable_between(&mut self, from: &str, to: &str) {
-        self.net.borrow_mut().enable_link(from, to);
-        self.net.borrow_mut().enable_link(to, from);
+    /// Crashes the node: it stops handling events until `recover_node`,
+    /// and the timers set by it are canceled. Does nothing if the node is already crashed.
+    pub fn crash_node(&mut self, node_id: &str) {
+        self.try_crash_node(node_id).unwrap_or_else(|e| panic!("{}", e))
     }
 
-    pub fn disable_all_links(&mut self) {
-        for from in &self.node_ids {
-            for to in &self.node_ids {
-                if from != to {
-                    self.net.borrow_mut().disable_link(from, to);
-                }
+    pub fn try_crash_node(&mut self, node_id: &str) -> Result<(), UnknownNodeError> {
+        let actor = self.node_actor(node_id)?;
+        if self.node_is_crashed(node_id) {
+            return Ok(());
+        }
+        let timers = actor.borrow_mut().crash();
+        self.cancel_node_timers(timers);
+        self.log(Some(node_id), LogKind::NodeCrashed);
+        self.crash_history.push((self.sim.time(), node_id.to_string(), true));
+        self.crashed_nodes.insert(node_id.to_string());
+        self.update_members();
+        self.net.borrow_mut().node_crashed(node_id);
+        Ok(())
+    }
+
+    /// Crashes a random alive node chosen with the simulation RNG and returns its id.
+    pub fn crash_random(&mut self) -> String {
+        self.try_crash_random().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_crash_random(&mut self) -> Result<String, NoAliveNodesError> {
+        let weights: Vec<(String, f64)> = self.node_ids.iter().map(|id| (id.clone(), 1.)).collect();
+        self.crash_weighted(&weights)
+    }
+
+    /// Like `crash_random`, but each node is chosen with probability proportional to its weight.
+    /// Nodes missing from `weights` are never chosen.
+    pub fn crash_random_weighted(&mut self, weights: &[(&str, f64)]) -> String {
+        self.try_crash_random_weighted(weights).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_crash_random_weighted(&mut self, weights: &[(&str, f64)]) -> Result<String, NoAliveNodesError> {
+        let weights: Vec<(String, f64)> = weights.iter().map(|(id, w)| (id.to_string(), *w)).collect();
+        self.crash_weighted(&weights)
+    }
+
+    fn crash_weighted(&mut self, weights: &[(String, f64)]) -> Result<String, NoAliveNodesError> {
+        let alive: Vec<&(String, f64)> = weights.iter()
+            .filter(|(id, w)| *w > 0. && self.nodes.contains_key(id) && !self.node_is_crashed(id))
+            .collect();
+        let total: f64 = alive.iter().map(|(_, w)| w).sum();
+        if alive.is_empty() {
+            return Err(NoAliveNodesError);
+        }
+        let mut point = self.sim.rand() * total;
+        let mut chosen = &alive[alive.len() - 1].0;
+        for (id, w) in alive.iter() {
+            if point < *w {
+                chosen = id;
+                break;
             }
+            point -= w;
         }
+        let chosen = chosen.clone();
+        self.crash_node(&chosen);
+        Ok(chosen)
     }
 
-    pub fn enable_all_links(&mut self) {
-        for from in &self.node_ids {
-            for to in &self.node_ids {
-                if from != to {
-                    self.net.borrow_mut().enable_link(from, to);
-                }
+    /// Brings a crashed node back with a fresh state and a new initial timer (`init` unless set by `add_node_with_init`).
+    /// Messages sent to the node while it was crashed, as well as ones still in flight
+    /// at the moment of recovery, are dropped; only messages sent after recovery are delivered.
+    /// Messages sent by the node before the crash that have not reached the network are dropped too.
+    /// State saved by the node with `Context::persist` is passed to `Node::restore_persistent`
+    /// after `Node::recover`, everything else is lost, provided the node implements `Node::reset`
+    /// (or `Node::recover`); the default keeps the state. Does nothing if the node is not crashed.
+    pub fn recover_node(&mut self, node_id: &str) {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+        if !self.node_is_crashed(node_id) {
+            return;
+        }
+        let timers = self.nodes[node_id].borrow_mut().recover();
+        self.cancel_node_timers(timers);
+        self.log(Some(node_id), LogKind::NodeRecovered);
+        self.crash_history.push((self.sim.time(), node_id.to_string(), false));
+        self.crashed_nodes.remove(node_id);
+        self.update_members();
+        // messages the node sent before the crash which have not reached the network yet are lost too
+        let node = ActorId::from(node_id);
+        self.sim.cancel_events(|e| *e.dest() == node || *e.src() == node);
+        self.net.borrow_mut().node_recovered(node_id);
+        if let Some(Some(name)) = self.init_timers.get(node_id).cloned() {
+            self.add_timer(node_id, &name);
+        }
+    }
+
+    /// Crashes the node right after the network receives the `n`-th message sent by it
+    /// from now on. Messages sent by the node after that are discarded, which leaves
+    /// e.g. a broadcast only partially delivered.
+    pub fn crash_after_n_sends(&mut self, node_id: &str, n: u64) {
+        if n == 0 {
+            self.crash_node(node_id);
+        } else {
+            self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+            self.net.borrow_mut().crash_after_sends(node_id, n);
+        }
+    }
+
+    /// Restarts the node cleanly: calls `Node::reset`, cancels the timers set by the node
+    /// and schedules its initial timer again. Unlike `crash_node` followed by `recover_node`,
+    /// the node never appears crashed, so messages in flight to or from it are still delivered,
+    /// and state saved with `Context::persist` is not restored.
+    pub fn reset_node(&mut self, node_id: &str) {
+        let timers = self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e)).borrow_mut().reset();
+        self.cancel_node_timers(timers);
+        if let Some(Some(name)) = self.init_timers.get(node_id).cloned() {
+            self.add_timer(node_id, &name);
+        }
+    }
+
+    fn cancel_node_timers(&mut self, mut timers: Vec<((ActorId, String), u64)>) {
+        // timers are kept in a map, cancel them in the order they were set
+        timers.sort_by_key(|(_, event_id)| *event_id);
+        for ((target, name), event_id) in timers {
+            self.sim.cancel_event(event_id);
+            self.notify_timer_observer(TimerEvent::Cancelled { time: self.sim.time(), node: target.to(), name });
+        }
+    }
+
+    pub fn schedule_crash(&mut self, node_id: &str, at: f64) {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+        self.add_control_action(ControlAction::CrashNode { node: node_id.to_string() }, at);
+    }
+
+    pub fn schedule_recovery(&mut self, node_id: &str, at: f64) {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+        self.add_control_action(ControlAction::RecoverNode { node: node_id.to_string() }, at);
+    }
+
+    /// Disables the link for the time window `[start, end)`. Overlapping windows are allowed,
+    /// the link is enabled again when no window covers the current time. Scheduled windows are
+    /// tracked apart from `disable_link` and partitions, so their end doesn't enable a link
+    /// disabled by those. Panics if `end` is before `start`.
+    pub fn schedule_link_down(&mut self, from: &str, to: &str, start: f64, end: f64) {
+        assert!(start <= end, "Link outage must not end before it starts, got [{}, {})", start, end);
+        let (from, to) = (from.to_string(), to.to_string());
+        self.add_control_action(ControlAction::LinkDown { from: from.clone(), to: to.clone() }, start);
+        self.add_control_action(ControlAction::LinkUp { from, to }, end);
+    }
+
+    /// Makes the link flap starting from the current time: it stays up for `up`, then goes down
+    /// for `down`, repeated `cycles` times, after which it is left up. Built on `schedule_link_down`,
+    /// so it composes with other scheduled link windows.
+    pub fn flap_link(&mut self, from: &str, to: &str, up: f64, down: f64, cycles: usize) {
+        let now = self.sim.time();
+        for i in 0..cycles {
+            let start = now + i as f64 * (up + down) + up;
+            self.schedule_link_down(from, to, start, start + down);
+        }
+    }
+
+    pub fn schedule_partition(&mut self, group1: &[&str], group2: &[&str], start: f64, end: f64) {
+        for n1 in group1 {
+            for n2 in group2 {
+                self.schedule_link_down(n1, n2, start, end);
+                self.schedule_link_down(n2, n1, start, end);
             }
         }
     }
 
-    pub fn make_partition(&mut self, group1: &[&str], group2: &[&str]) {
-        self.net.borrow_mut().make_partition(group1, group2);
+    fn apply_control_action(&mut self, action: ControlAction) {
+        match action {
+            ControlAction::CrashNode { node } => self.crash_node(&node),
+            ControlAction::RecoverNode { node } => self.recover_node(&node),
+            ControlAction::LinkDown { from, to } => self.net.borrow_mut().begin_link_outage(&from, &to),
+            ControlAction::LinkUp { from, to } => self.net.borrow_mut().end_link_outage(&from, &to),
+        }
     }
 
-    pub fn reset_network(&mut self) {
-        self.net.borrow_mut().reset_network();
+    fn add_control_action(&mut self, action: ControlAction, at: f64) {
+        let control = ActorId::from("control");
+        let delay = (at - self.sim.time()).max(0.);
+        self.sim.add_event(SysEvent::Control { action }, control.clone(), control, delay);
     }
 
-    pub fn get_network_message_count(&self) -> u64 {
-        self.net.borrow().get_message_count()
+    pub fn node_is_crashed(&self, node_id: &str) -> bool {
+        self.crashed_nodes.contains(node_id)
     }
 
-    pub fn send(&mut self, msg: M, src: &str, dest: &str) {
-        let event = SysEvent::MessageSend {
-            msg,
-            src: ActorId::from(src),
-            dest: ActorId::from(dest),
-        };
-        self.sim.add_event(event, ActorId::from(src), ActorId::from("net"), 0.0);
+    pub fn node_count(&self) -> u32 {
+        self.nodes.len() as u32
     }
 
-    pub fn send_local(&mut self, msg: M, dest: &str) {
-        let src = ActorId::from(&format!("local@{}", dest));
-        let dest = ActorId::from(dest);
-        let event = SysEvent::LocalMessageReceive { msg };
-        self.sim.add_event(event, src, dest, 0.0);
+    /// Lets `f` choose which event to process next by its index among the eligible events,
+    /// i.e. the ones scheduled at the earliest pending time. Without an adversary
+    /// events are processed in time order, ties are broken in scheduling order.
+    pub fn set_adversary(&mut self, f: Adversary<SysEvent<M>>) {
+        self.sim.set_adversary(Some(f), 0.);
     }
 
-    pub fn step(&mut self) -> bool {
-        self.sim.step()
+    /// Like `set_adversary`, but events scheduled within `window` from the earliest one
+    /// are also eligible. Events passed over for a later one are processed at the later time.
+    pub fn set_adversary_with_window(&mut self, f: Adversary<SysEvent<M>>, window: f64) {
+        self.sim.set_adversary(Some(f), window);
     }
 
-    pub fn steps(&mut self, step_count: u32) {
-        self.sim.steps(step_count)
+    pub fn clear_adversary(&mut self) {
+        self.sim.set_adversary(None, 0.);
     }
 
-    pub fn step_until_no_events(&mut self) {
-        self.sim.step_until_no_events()
+    /// When enabled, events scheduled at the same time are processed starting with the node
+    /// served least recently rather than in scheduling order. The order is still deterministic.
+    pub fn set_fair_scheduling(&mut self, enabled: bool) {
+        self.sim.set_fair_scheduling(enabled);
     }
 
-    pub fn step_while(&mut self, f: fn(&SysEvent<M>) -> bool) {
-        self.sim.step_while(f);
+    /// Raises delays of timers set by nodes (and periodic timer intervals) to at least `min`,
+    /// logging each adjustment, so that a timer re-armed with zero delay cannot starve other events.
+    /// Not limited by default.
+    pub fn set_min_timer_delay(&mut self, min: f64) {
+        self.min_timer_delay = min;
+        for actor in self.nodes.values() {
+            actor.borrow_mut().set_min_timer_delay(min);
+        }
     }
 
-    pub fn get_local_events(&self, node_id: &str) -> Vec<LocalEvent<M>> {
-        let node = self.nodes.get(node_id).unwrap().borrow();
-        node.get_local_events()
+    /// Makes the node compute-bound: delays of timers set by the node are multiplied by `factor`,
+    /// and its messages take `factor` times longer to reach the network.
+    /// Independent from `jail_node`, which delays messages in the network itself.
+    /// Use `factor` 1 to restore the normal speed.
+    pub fn slow_node(&mut self, node_id: &str, factor: f64) {
+        assert!(factor > 0., "Slowdown factor must be positive, got {}", factor);
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e)).borrow_mut().set_slowdown(factor);
     }
 
-    pub fn count_undelivered_events(&mut self) -> usize {
-        self.sim.read_undelivered_events().len()
+    /// Sets how messages sent by nodes to themselves are delivered.
+    /// By default they are delivered immediately and bypass the network.
+    pub fn set_loopback_mode(&mut self, mode: LoopbackMode) {
+        self.loopback_mode = mode;
+        for actor in self.nodes.values() {
+            actor.borrow_mut().set_loopback_mode(mode);
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: f64) {
+        self.net.borrow_mut().set_delay(delay);
     }
-}
\ No newline at end of file
+
+    pub fn set_delays(&mut self, min_delay: f64, max_delay: f64) {
+        self.net.borrow_mut().set_delays(min_delay, max_delay);
+    }
+
+    /// Draws message delays from `f` instead of the uniform `[min_delay, max_delay]` range.
+    /// Per-link delays still take precedence.
+    pub fn set_delay_fn(&mut self, f: DelayFn) {
+        self.net.borrow_mut().set_delay_fn(Some(f));
+    }
+
+    /// Like `set_delay_fn`, but the delay can depend on the message, e.g. on its size.
+    /// Takes precedence over `set_delay_fn`, per-link delays still take precedence over both.
+    pub fn set_delay_fn_msg(&mut self, f: MessageDelayFn<M>) {
+        self.net.borrow_mut().set_message_delay_fn(Some(f));
+    }
+
+    pub fn set_link_delay(&mut self, from: &str, to: &str, delay: f64) {
+        self.net.borrow_mut().set_link_delay(from, to, delay);
+    }
+
+    pub fn set_link_delays(&mut self, from: &str, to: &str, min_delay: f64, max_delay: f64) {
+        self.net.borrow_mut().set_link_delays(from, to, min_delay, max_delay);
+    }
+
+    /// Limits the link throughput; messages on a busy link wait for the previous ones
+    /// to be transmitted. Message size is 1 byte unless set with `set_message_size_fn`.
+    pub fn set_link_bandwidth(&mut self, from: &str, to: &str, bytes_per_sec: f64) {
+        self.net.borrow_mut().set_link_bandwidth(from, to, bytes_per_sec);
+    }
+
+    /// Limits the number of messages in flight on the link. Messages sent while
+    /// the window is full wait in order until earlier messages are delivered.
+    /// Panics if `max` is 0. The window is removed by `reset_network`.
+    pub fn set_link_window(&mut self, from: &str, to: &str, max: usize) {
+        self.net.borrow_mut().set_link_window(from, to, max);
+    }
+
+    /// Adds `extra_delay` to the delivery time of the next message delivered on the link.
+    pub fn delay_next(&mut self, from: &str, to: &str, extra_delay: f64) {
+        self.net.borrow_mut().delay_next(from, to, extra_delay);
+    }
+
+    /// Drops the next `count` messages sent on the link regardless of the drop rate.
+    pub fn drop_next(&mut self, from: &str, to: &str, count: usize) {
+        self.net.borrow_mut().drop_next(from, to, count);
+    }
+
+    /// Decides whether to drop the next messages sent on the link from `decisions`
+    /// (true means drop) instead of the random drop rate, one decision per message.
+    /// Once the decisions run out, messages are dropped randomly again.
+    pub fn force_drop(&mut self, from: &str, to: &str, decisions: Vec<bool>) {
+        self.net.borrow_mut().force_drop(from, to, decisions);
+    }
+
+    /// Like `force_drop`, but decides whether the next messages delivered on the link are duplicated.
+    pub fn force_dupl(&mut self, from: &str, to: &str, decisions: Vec<bool>) {
+        self.net.borrow_mut().force_dupl(from, to, decisions);
+    }
+
+    pub fn set_message_size_fn(&mut self, f: Box<dyn Fn(&M) -> usize>) {
+        self.net.borrow_mut().set_message_size_fn(f);
+    }
+
+    /// When enabled, messages between each pair of nodes are delivered in the order they were sent.
+    pub fn set_fifo(&mut self, enabled: bool) {
+        self.net.borrow_mut().set_fifo(enabled);
+    }
+
+    /// Holds the next `perm.len()` messages sent on the link and then delivers them so that
+    /// the i-th delivered message is the `perm[i]`-th one sent (counting from 0), after which
+    /// the link behaves normally again. Held messages wait until the last of them is sent,
+    /// they are counted as in flight and discarded by `reset_network`. Panics if `perm` is not a permutation.
+    pub fn set_link_permutation(&mut self, from: &str, to: &str, perm: Vec<usize>) {
+        self.net.borrow_mut().set_link_permutation(from, to, perm);
+    }
+
+    /// Lets the network deliver a message before at most `k` messages sent earlier on the same link,
+    /// the amount of reordering is chosen with the simulation RNG. With `k` = 0 links are FIFO.
+    /// Ignored if `set_fifo` is enabled.
+    pub fn set_reorder_window(&mut self, k: usize) {
+        self.net.borrow_mut().set_reorder_window(Some(k));
+    }
+
+    pub fn clear_reorder_window(&mut self) {
+        self.net.borrow_mut().set_reorder_window(None);
+    }
+
+    pub fn set_drop_rate(&mut self, drop_rate: f64) {
+        self.net.borrow_mut().set_drop_rate(drop_rate);
+    }
+
+    /// Overrides the drop rate for messages going from `from` to `to` until `reset_network`.
+    pub fn set_link_drop_rate(&mut self, from: &str, to: &str, drop_rate: f64) {
+        self.net.borrow_mut().set_link_drop_rate(from, to, drop_rate);
+    }
+
+    pub fn set_dupl_rate(&mut self, dupl_rate: f64) {
+        self.net.borrow_mut().set_dupl_rate(dupl_rate);
+    }
+
+    /// Sets the range for the total number of copies delivered when a message is duplicated,
+    /// 2 to 3 by default. Whether a message is duplicated at all is decided by `set_dupl_rate`.
+    /// Each copy gets its own delivery delay, subject to the same link rules as other messages
+    /// (bandwidth, FIFO, reordering), and arrives no earlier than the original message.
+    pub fn set_dupl_count(&mut self, min: u32, max: u32) {
+        assert!(1 <= min && min <= max, "Invalid duplication count range {}..={}", min, max);
+        self.net.borrow_mut().set_dupl_count(min, max);
+    }
+
+    /// Corrupted messages are replaced with the result of the function set by `set_corrupt_fn`
+    /// and are still delivered. Panics if a non-zero rate is set before the function.
+    pub fn set_corrupt_rate(&mut self, corrupt_rate: f64) {
+        self.net.borrow_mut().set_corrupt_rate(corrupt_rate);
+    }
+
+    pub fn set_corrupt_fn(&mut self, f: CorruptFn<M>) {
+        self.net.borrow_mut().set_corrupt_fn(f);
+    }
+
+    /// Replaces each message sent by the node with the messages returned by `f`.
+    pub fn make_byzantine(&mut self, node_id: &str, f: ByzantineFn<M>) {
+        self.net.borrow_mut().make_byzantine(node_id, f);
+    }
+
+    /// Registers a read-only observer called for each decision the network makes about a message.
+    pub fn set_network_tap(&mut self, f: TapFn<M>) {
+        self.net.borrow_mut().set_tap(Some(f));
+    }
+
+    pub fn clear_network_tap(&mut self) {
+        self.net.borrow_mut().set_tap(None);
+    }
+
+    /// Removes network randomness: sets drop, duplication and corruption rates to 0,
+    /// sets the global delay to 1.0 (clearing the delay function) and enables FIFO delivery.
+    /// Per-link settings and link states are left unchanged. Also removes the adversary and
+    /// disables fair scheduling, so events with equal time are processed in the order they were scheduled.
+    pub fn deterministic_mode(&mut self) {
+        self.sim.set_adversary(None, 0.);
+        self.sim.set_fair_scheduling(false);
+        let mut net = self.net.borrow_mut();
+        net.set_drop_rate(0.);
+        net.set_dupl_rate(0.);
+        net.set_corrupt_rate(0.);
+        net.set_delay(1.);
+        net.set_delay_fn(None);
+        net.set_message_delay_fn(None);
+        net.set_fifo(true);
+    }
+
+    /// Adds `extra_delay` to every message sent or received by the node,
+    /// on top of the link or global delay. The node keeps participating, just slower.
+    pub fn jail_node(&mut self, node_id: &str, extra_delay: f64) {
+        self.net.borrow_mut().jail_node(node_id, extra_delay);
+    }
+
+    pub fn unjail_node(&mut self, node_id: &str) {
+        self.net.borrow_mut().unjail_node(node_id);
+    }
+
+    pub fn drop_incoming(&mut self, node_id: &str) {
+        self.net.borrow_mut().drop_incoming(node_id);
+    }
+
+    pub fn pass_incoming(&mut self, node_id: &str) {
+        self.net.borrow_mut().pass_incoming(node_id);
+    }
+
+    /// Drops messages to the node for which `f` returns false. Cleared by `pass_incoming`.
+    pub fn filter_incoming(&mut self, node_id: &str, f: FilterFn<M>) {
+        self.net.borrow_mut().filter_incoming(node_id, f);
+    }
+
+    pub fn drop_outgoing(&mut self, node_id: &str) {
+        self.net.borrow_mut().drop_outgoing(node_id);
+    }
+
+    pub fn pass_outgoing(&mut self, node_id: &str) {
+        self.net.borrow_mut().pass_outgoing(node_id);
+    }
+
+    /// Drops messages from the node for which `f` returns false. Cleared by `pass_outgoing`.
+    pub fn filter_outgoing(&mut self, node_id: &str, f: FilterFn<M>) {
+        self.net.borrow_mut().filter_outgoing(node_id, f);
+    }
+
+    pub fn disconnect_node(&mut self, node_id: &str) {
+        self.net.borrow_mut().disconnect_node(node_id);
+    }
+
+    pub fn connect_node(&mut self, node_id: &str) {
+        self.net.borrow_mut().connect_node(node_id);
+    }
+
+    pub fn disable_link(&mut self, from: &str, to: &str) {
+        self.net.borrow_mut().disable_link(from, to);
+    }
+
+    pub fn enable_link(&mut self, from: &str, to: &str) {
+        self.net.borrow_mut().enable_link(from, to);
+    }
+
+
+    pub fn enable_between(&mut self, from: &str, to: &str) {
+        self.net.borrow_mut().enable_link(from, to);
+        self.net.borrow_mut().enable_link(to, from);
+    }
+
+    pub fn disable_all_links(&mut self) {
+        for from in &self.node_ids {
+            for to in &self.node_ids {
+                if from != to {
+                    self.net.borrow_mut().disable_link(from, to);
+                }
+            }
+        }
+    }
+
+    pub fn enable_all_links(&mut self) {
+        for from in &self.node_ids {
+            for to in &self.node_ids {
+                if from != to {
+                    self.net.borrow_mut().enable_link(from, to);
+                }
+            }
+        }
+    }
+
+    /// Returns ids of nodes in the group `prefix`. Node ids are treated as paths
+    /// separated by `/` (e.g. `dc1/rack2/node5`), and a node belongs to the group
+    /// if its id equals `prefix` or starts with `prefix` followed by `/`.
+    /// So `dc1` matches `dc1/rack2/node5` but not `dc10/node1`.
+    pub fn group_nodes(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.trim_end_matches('/');
+        self.node_ids.iter()
+            .filter(|id| *id == prefix || (id.starts_with(prefix) && id[prefix.len()..].starts_with('/')))
+            .cloned()
+            .collect()
+    }
+
+    /// Disconnects all nodes of the group, see `group_nodes` for the matching rules.
+    pub fn disconnect_group(&mut self, prefix: &str) {
+        for id in self.group_nodes(prefix) {
+            self.disconnect_node(&id);
+        }
+    }
+
+    pub fn connect_group(&mut self, prefix: &str) {
+        for id in self.group_nodes(prefix) {
+            self.connect_node(&id);
+        }
+    }
+
+    /// Crashes all alive nodes of the group, see `group_nodes` for the matching rules.
+    pub fn crash_group(&mut self, prefix: &str) {
+        for id in self.group_nodes(prefix) {
+            if !self.node_is_crashed(&id) {
+                self.crash_node(&id);
+            }
+        }
+    }
+
+    pub fn recover_group(&mut self, prefix: &str) {
+        for id in self.group_nodes(prefix) {
+            if self.node_is_crashed(&id) {
+                self.recover_node(&id);
+            }
+        }
+    }
+
+    /// Enables links in both directions between all nodes of the two groups.
+    pub fn enable_between_groups(&mut self, prefix1: &str, prefix2: &str) {
+        for n1 in self.group_nodes(prefix1) {
+            for n2 in self.group_nodes(prefix2) {
+                if n1 != n2 {
+                    self.enable_between(&n1, &n2);
+                }
+            }
+        }
+    }
+
+    /// Disables links in both directions between all nodes of the two groups.
+    pub fn partition_groups(&mut self, prefix1: &str, prefix2: &str) {
+        let group1 = self.group_nodes(prefix1);
+        let group2 = self.group_nodes(prefix2);
+        let group1: Vec<&str> = group1.iter().map(|id| id.as_str()).collect();
+        let group2: Vec<&str> = group2.iter().map(|id| id.as_str()).collect();
+        self.make_partition(&group1, &group2);
+    }
+
+    pub fn make_partition(&mut self, group1: &[&str], group2: &[&str]) {
+        self.net.borrow_mut().make_partition(group1, group2);
+    }
+
+    /// Disables all links between nodes from different groups.
+    /// Links of nodes not listed in any group are left as they are.
+    pub fn make_partitions(&mut self, groups: &[&[&str]]) {
+        self.net.borrow_mut().make_partitions(groups);
+    }
+
+    /// Enables the links disabled by `make_partition`, `make_partitions` and `partition_groups`
+    /// and returns the messages dropped on them since they were disabled. Links disabled otherwise,
+    /// e.g. with `disable_link` or `schedule_link_down`, stay disabled.
+    pub fn heal_partition(&mut self) -> Vec<DroppedMessage<M>> {
+        self.net.borrow_mut().heal_partition()
+    }
+
+    /// Enables all links, removes message filters, link drop rates and link windows,
+    /// and clears the network statistics along with the dropped and discarded messages
+    /// recorded for `undelivered_events`.
+    /// Returns the messages held back by link windows and permutations, they are never delivered.
+    pub fn reset_network(&mut self) -> Vec<DroppedMessage<M>> {
+        self.sim.clear_discarded_events();
+        let time = self.sim.time();
+        self.net.borrow_mut().reset_network(time)
+    }
+
+    pub fn get_network_message_count(&self) -> u64 {
+        self.net.borrow().get_message_count()
+    }
+
+    /// Number of messages sent through the network, same as `get_network_message_count`.
+    pub fn messages_sent(&self) -> u64 {
+        self.net.borrow().get_message_count()
+    }
+
+    /// Number of messages scheduled for delivery by the network, each duplicate is counted separately.
+    pub fn messages_delivered(&self) -> u64 {
+        self.net.borrow().get_delivered_count()
+    }
+
+    /// Number of sent messages dropped by the network for any reason.
+    pub fn messages_dropped(&self) -> u64 {
+        self.net.borrow().get_dropped_count()
+    }
+
+    /// Total size of delivered messages as measured by `set_message_size_fn`.
+    pub fn get_network_byte_count(&self) -> u64 {
+        self.net.borrow().get_byte_count()
+    }
+
+    pub fn link_enabled(&self, from: &str, to: &str) -> bool {
+        self.net.borrow().link_enabled(from, to)
+    }
+
+    /// Returns (from, to, enabled) for every ordered pair of distinct nodes.
+    pub fn all_links(&self) -> Vec<(String, String, bool)> {
+        let net = self.net.borrow();
+        let mut links = Vec::new();
+        for from in &self.node_ids {
+            for to in &self.node_ids {
+                if from != to {
+                    links.push((from.clone(), to.clone(), net.link_enabled(from, to)));
+                }
+            }
+        }
+        links
+    }
+
+    pub fn topology_dot(&self) -> String {
+        let net = self.net.borrow();
+        let mut dot = String::from("digraph topology {\n");
+        for id in &self.node_ids {
+            if self.node_is_crashed(id) {
+                dot.push_str(&format!("    \"{}\" [style=dashed, color=red];\n", id));
+            } else {
+                dot.push_str(&format!("    \"{}\";\n", id));
+            }
+        }
+        for from in &self.node_ids {
+            for to in &self.node_ids {
+                if from != to && net.link_enabled(from, to) {
+                    match net.get_link_delays(from, to) {
+                        Some((min_delay, max_delay)) if min_delay == max_delay => {
+                            dot.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\"];\n", from, to, min_delay));
+                        }
+                        Some((min_delay, max_delay)) => {
+                            dot.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}-{}\"];\n", from, to, min_delay, max_delay));
+                        }
+                        None => {
+                            dot.push_str(&format!("    \"{}\" -> \"{}\";\n", from, to));
+                        }
+                    }
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Returns the nodes which can be reached from the node via enabled links,
+    /// possibly through other nodes. Crashed nodes are neither reachable nor relay messages.
+    pub fn reachable_from(&self, node_id: &str) -> Vec<String> {
+        let net = self.net.borrow();
+        let mut visited = HashSet::new();
+        visited.insert(node_id.to_string());
+        let mut queue = vec![node_id.to_string()];
+        while let Some(from) = queue.pop() {
+            for to in &self.node_ids {
+                if !visited.contains(to) && !self.node_is_crashed(to) && net.link_enabled(&from, to) {
+                    visited.insert(to.clone());
+                    queue.push(to.clone());
+                }
+            }
+        }
+        self.node_ids.iter()
+            .filter(|id| *id != node_id && visited.contains(*id))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a copy of the scheduled events as (time, src, dest, event), in processing order.
+    pub fn pending_events(&self) -> Vec<(f64, ActorId, ActorId, SysEvent<M>)> {
+        self.sim.pending_events().iter()
+            .map(|e| (e.time(), e.src().clone(), e.dest().clone(), e.event().clone()))
+            .collect()
+    }
+
+    /// Renders events recorded since `record_trace` as a space-time diagram with one column
+    /// per node and time increasing downwards. `o` marks a send, arrows show message deliveries,
+    /// `T` fired timers, `L` local messages, `X` crashes and `R` recoveries.
+    pub fn spacetime_ascii(&self) -> String {
+        const WIDTH: usize = 12;
+        let column = |id: &ActorId| self.node_ids.iter().position(|n| *n == id.to());
+        let center = |col: usize| col * WIDTH + WIDTH / 2;
+        let blank = || {
+            let mut line = vec![' '; self.node_ids.len() * WIDTH];
+            for col in 0..self.node_ids.len() {
+                line[center(col)] = '|';
+            }
+            line
+        };
+        let mut rows: Vec<(f64, Vec<char>, String)> = Vec::new();
+        for e in self.sim.history() {
+            let mut line = blank();
+            let label = match e.event() {
+                SysEvent::MessageSend { msg, src, dest } => match column(src) {
+                    Some(col) => {
+                        line[center(col)] = 'o';
+                        format!("{} -> {} {:?}", src, dest, msg)
+                    }
+                    None => continue,
+                },
+                SysEvent::MessageReceive { msg, src, dest } => match (column(src), column(dest)) {
+                    (Some(from), Some(to)) if from != to => {
+                        let (start, end) = (center(from.min(to)), center(from.max(to)));
+                        for c in line.iter_mut().take(end).skip(start + 1) {
+                            *c = '-';
+                        }
+                        line[center(from)] = 'o';
+                        line[center(to)] = if to > from { '>' } else { '<' };
+                        format!("{:?}", msg)
+                    }
+                    (_, Some(to)) => {
+                        line[center(to)] = '>';
+                        format!("{} {:?}", src, msg)
+                    }
+                    _ => continue,
+                },
+                SysEvent::TimerFired { name, .. } | SysEvent::PeriodicTimerFired { name, .. } => match column(e.dest()) {
+                    Some(col) => {
+                        line[center(col)] = 'T';
+                        name.clone()
+                    }
+                    None => continue,
+                },
+                SysEvent::LocalMessageReceive { msg } => match column(e.dest()) {
+                    Some(col) => {
+                        line[center(col)] = 'L';
+                        format!("{:?}", msg)
+                    }
+                    None => continue,
+                },
+                _ => continue,
+            };
+            rows.push((e.time(), line, label));
+        }
+        for (time, node, crashed) in &self.crash_history {
+            if let Some(col) = column(&ActorId::from(node)) {
+                let mut line = blank();
+                line[center(col)] = if *crashed { 'X' } else { 'R' };
+                let label = if *crashed { "CRASHED" } else { "RECOVERED" };
+                rows.push((*time, line, label.to_string()));
+            }
+        }
+        rows.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut result = format!("{:>9} ", "");
+        for id in &self.node_ids {
+            result.push_str(&format!("{:^width$}", id, width = WIDTH));
+        }
+        result.push('\n');
+        for (time, line, label) in rows {
+            let line: String = line.into_iter().collect();
+            result.push_str(&format!("{:>9.3} {} {}\n", time, line, label));
+        }
+        result
+    }
+
+    /// Number of messages accepted by the network and not delivered yet: scheduled for delivery
+    /// at or after the current time, or held back by a link window or permutation.
+    pub fn in_flight_count(&self) -> usize {
+        self.sim.pending_events().iter()
+            .filter(|e| matches!(e.event(), SysEvent::MessageReceive { .. }))
+            .count()
+            + self.net.borrow().held_message_count()
+    }
+
+    pub fn in_flight_between(&self, from: &str, to: &str) -> usize {
+        self.sim.pending_events().iter()
+            .filter(|e| match e.event() {
+                SysEvent::MessageReceive { src, dest, .. } => src.to() == from && dest.to() == to,
+                _ => false,
+            })
+            .count()
+            + self.net.borrow().held_messages(from, to)
+    }
+
+    /// Counts messages sent by the node, delivered to it by the network (including duplicates)
+    /// and sent by it but dropped by the network. Cleared by `reset_network`.
+    pub fn node_message_stats(&self, node_id: &str) -> MessageStats {
+        self.net.borrow().get_node_stats(node_id)
+    }
+
+    /// Total logical cost of the events handled by the node, as reported by `Node::handler_cost`.
+    pub fn node_work(&self, node_id: &str) -> f64 {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e)).borrow().work()
+    }
+
+    /// Delivery delays of messages scheduled by the network since the last `reset_network`.
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.net.borrow().get_latency_stats()
+    }
+
+    /// Retains every observed delivery delay so that `latency_percentile` can be computed.
+    /// Disabled by default to save memory; enabling or disabling clears the retained delays.
+    pub fn keep_latency_samples(&mut self, enabled: bool) {
+        self.net.borrow_mut().set_keep_latency_samples(enabled);
+    }
+
+    /// Returns the `p`-th percentile (0 to 100) of delivery delays, or 0 if there are none yet.
+    /// Requires `keep_latency_samples` to be enabled.
+    pub fn latency_percentile(&self, p: f64) -> f64 {
+        let stats = self.net.borrow().get_latency_stats();
+        assert!(stats.samples.is_some(), "Latency samples are not kept, call keep_latency_samples first");
+        stats.percentile(p).unwrap_or(0.)
+    }
+
+    /// Sends the message to the member of `group` with the smallest expected link delay from `src`
+    /// (see `Network::expected_delay`) among those which are alive and whose link from `src` is enabled.
+    /// Ties are broken by the order in `group`. Returns the chosen destination, or None if no member
+    /// is reachable, in which case the message is dropped and logged.
+    pub fn anycast(&mut self, msg: M, src: &str, group: &[&str]) -> Option<String> {
+        let dest = {
+            let net = self.net.borrow();
+            group.iter()
+                .filter(|id| self.nodes.contains_key(**id) && !self.node_is_crashed(id) && net.link_enabled(src, id))
+                .map(|id| (net.expected_delay(src, id), *id))
+                .fold(None, |best: Option<(f64, &str)>, (delay, id)| match best {
+                    Some((best_delay, _)) if best_delay <= delay => best,
+                    _ => Some((delay, id)),
+                })
+                .map(|(_, id)| id.to_string())
+        };
+        match &dest {
+            Some(dest) => self.send(msg, src, dest),
+            None => self.log(Some(src), LogKind::AnycastUnreachable {
+                group: group.iter().map(|id| id.to_string()).collect(),
+                msg: format!("{:?}", msg),
+            }),
+        }
+        dest
+    }
+
+    /// Messages from a crashed node are silently discarded and only logged,
+    /// so they are not counted by the network.
+    pub fn send(&mut self, msg: M, src: &str, dest: &str) {
+        if self.node_is_crashed(src) {
+            self.log(Some(src), LogKind::SendFromCrashedNode { dest: dest.to_string(), msg: format!("{:?}", msg) });
+            return;
+        }
+        let event = SysEvent::MessageSend {
+            msg,
+            src: ActorId::from(src),
+            dest: ActorId::from(dest),
+        };
+        self.sim.add_event(event, ActorId::from(src), ActorId::from("net"), 0.0);
+    }
+
+    /// Schedules delivery of the message to `dest` at time `at` (or now, if `at` is in the past)
+    /// bypassing the network. By design it ignores network faults, delays and link states,
+    /// and is not counted as a sent message.
+    pub fn inject_receive(&mut self, msg: M, src: &str, dest: &str, at: f64) {
+        let event = SysEvent::MessageReceive {
+            msg,
+            src: ActorId::from(src),
+            dest: ActorId::from(dest),
+        };
+        let delay = (at - self.sim.time()).max(0.);
+        self.sim.add_event(event, ActorId::from("net"), ActorId::from(dest), delay);
+    }
+
+    pub fn broadcast(&mut self, msg: M, src: &str) {
+        for dest in self.node_ids.clone() {
+            if dest != src {
+                self.send(msg.clone(), src, &dest);
+            }
+        }
+    }
+
+    pub fn multicast(&mut self, msg: M, src: &str, dests: &[&str]) {
+        for dest in dests {
+            self.send(msg.clone(), src, dest);
+        }
+    }
+
+    pub fn send_local(&mut self, msg: M, dest: &str) {
+        self.send_local_after(msg, dest, 0.0);
+    }
+
+    /// Delivers the local message after `delay`. Like other local messages,
+    /// it is discarded if the destination is crashed at the time of delivery.
+    pub fn send_local_after(&mut self, msg: M, dest: &str, delay: f64) {
+        let src = ActorId::from(&format!("local@{}", dest));
+        let dest = ActorId::from(dest);
+        let event = SysEvent::LocalMessageReceive { msg };
+        self.sim.add_event(event, src, dest, delay);
+    }
+
+    /// Schedules a sequence of local messages. Each delay is counted from the previous
+    /// message, the first one from the current time.
+    pub fn load_workload(&mut self, dest: &str, script: &[(f64, M)]) {
+        let mut delay = 0.;
+        for (step, msg) in script {
+            delay += step;
+            self.send_local_after(msg.clone(), dest, delay);
+        }
+    }
+
+    /// Registers a client which talks to the node. Local messages sent with `client_send`
+    /// are delivered to the node with `Context::client` set, and its `Context::send_local` outputs
+    /// while handling them, as well as those sent with `Context::send_to_client`, are added
+    /// to the client queue returned by `client_outputs`. Outputs also stay in the node's local events.
+    pub fn register_client(&mut self, name: &str, node: &str) {
+        self.node_actor(node).unwrap_or_else(|e| panic!("{}", e));
+        self.client_nodes.insert(name.to_string(), node.to_string());
+        self.clients.borrow_mut().entry(name.to_string()).or_default();
+    }
+
+    /// Sends a local message from the client to its node, see `register_client`.
+    pub fn client_send(&mut self, name: &str, msg: M) {
+        let node = self.client_nodes.get(name).unwrap_or_else(|| panic!("Unknown client {}", name));
+        let event = SysEvent::LocalMessageReceive { msg };
+        self.sim.add_event(event, ActorId::from(&format!("client@{}", name)), ActorId::from(node), 0.0);
+    }
+
+    /// Outputs delivered to the client so far, in the order they were produced.
+    pub fn client_outputs(&self, name: &str) -> Vec<M> {
+        self.clients.borrow().get(name).cloned().unwrap_or_default()
+    }
+
+    /// Logs messages for which `f` returns true when the network accepts them from the sender
+    /// and when they are delivered, as `MessageSent` and `MessageReceived` records.
+    pub fn trace_if(&mut self, f: FilterFn<M>) {
+        self.trace_filter = Some(f);
+    }
+
+    pub fn clear_trace_if(&mut self) {
+        self.trace_filter = None;
+    }
+
+    pub fn record_trace(&mut self) {
+        self.sim.record_trace();
+    }
+
+    pub fn get_trace(&self) -> Vec<TraceEntry> {
+        self.sim.trace()
+    }
+
+    /// Returns the first trace entry which could not be matched by a system created with `replay`,
+    /// after which the replay stopped. The divergence is also logged.
+    pub fn replay_divergence(&self) -> Option<TraceEntry> {
+        self.sim.replay_divergence().cloned()
+    }
+
+    /// Registers a check which is run after each processed event.
+    /// A failed check panics with the invariant name, the event and the simulation time.
+    pub fn add_invariant(&mut self, name: &str, f: Invariant<M>) {
+        self.invariants.push((name.to_string(), f));
+    }
+
+    /// Calls `f` with the number of processed events and the current time
+    /// after every `every` events.
+    pub fn on_progress(&mut self, every: u64, f: ProgressFn) {
+        assert!(every > 0, "Progress interval must be positive");
+        self.progress = Some((every, f));
+    }
+
+    /// Stops all stepping methods from processing events scheduled after time `t`,
+    /// as if there were no events left. Use `time_limit_reached` to tell the two cases apart.
+    pub fn set_time_limit(&mut self, t: f64) {
+        self.time_limit = Some(t);
+        self.time_limit_reached = false;
+    }
+
+    /// Removes the limit set with `set_time_limit`, so stepping continues past it.
+    pub fn clear_time_limit(&mut self) {
+        self.time_limit = None;
+        self.time_limit_reached = false;
+    }
+
+    /// Returns true if stepping was stopped by the limit set with `set_time_limit`
+    /// since the limit was last set or cleared.
+    pub fn time_limit_reached(&self) -> bool {
+        self.time_limit_reached
+    }
+
+    pub fn step(&mut self) -> bool {
+        self.step_detailed().is_some()
+    }
+
+    /// Processes the next event and returns it, or None if there are no events left
+    /// or the next one is past the time limit (see `time_limit_reached`).
+    pub fn step_detailed(&mut self) -> Option<ProcessedEvent<M>> {
+        if let (Some(limit), Some(next_time)) = (self.time_limit, self.sim.next_event_time()) {
+            if next_time > limit {
+                self.time_limit_reached = true;
+                return None;
+            }
+        }
+        let diverged = self.sim.replay_divergence().is_some();
+        let result = self.sim.step_detailed();
+        if let (false, Some(entry)) = (diverged, self.sim.replay_divergence()) {
+            let kind = LogKind::ReplayDiverged { src: entry.src.clone(), dest: entry.dest.clone(), event: entry.event.clone() };
+            self.log(None, kind);
+        }
+        self.finish_step(result)
+    }
+
+    /// Processes pending message events (`MessageSend`, `MessageReceive`, `LocalMessageReceive`
+    /// and network acks) in time order until none are left, while timers and control actions stay queued.
+    /// Timers due in the meantime fire late, when normal stepping resumes.
+    /// Returns the number of processed events, or an error if message events are still pending
+    /// after `max_steps` of them, e.g. because nodes keep answering each other.
+    pub fn step_messages_only(&mut self, max_steps: u64) -> Result<u64, TooManyStepsError> {
+        let limit = self.time_limit.unwrap_or(f64::INFINITY);
+        let is_message = |e: &SysEvent<M>| matches!(e,
+            SysEvent::MessageSend { .. } | SysEvent::MessageReceive { .. }
+            | SysEvent::LocalMessageReceive { .. } | SysEvent::MessageAcked { .. });
+        let mut count = 0;
+        while count < max_steps {
+            match self.sim.step_matching(|e| is_message(e.event()) && e.time() <= limit) {
+                Some(entry) => self.finish_step(Some(entry)),
+                None => break,
+            };
+            count += 1;
+        }
+        let pending = self.sim.pending_events().into_iter().filter(|e| is_message(e.event())).collect::<Vec<_>>();
+        if pending.iter().any(|e| e.time() > limit) {
+            self.time_limit_reached = true;
+        }
+        if pending.iter().any(|e| e.time() <= limit) {
+            let pending_events = pending.len();
+            return Err(TooManyStepsError { max_steps, time: self.sim.time(), pending_events });
+        }
+        Ok(count)
+    }
+
+    /// Processes the earliest pending event destined for the node, leaving events for other
+    /// actors (including messages still in the network) queued in their order. This may advance
+    /// the clock to that event's time, so events skipped over are processed late afterwards.
+    /// Returns false if there are no events for the node.
+    pub fn step_node(&mut self, node_id: &str) -> bool {
+        self.node_actor(node_id).unwrap_or_else(|e| panic!("{}", e));
+        let dest = ActorId::from(node_id);
+        let limit = self.time_limit.unwrap_or(f64::INFINITY);
+        match self.sim.step_matching(|e| *e.dest() == dest && e.time() <= limit) {
+            Some(entry) => self.finish_step(Some(entry)).is_some(),
+            None => {
+                if self.sim.pending_events().iter().any(|e| *e.dest() == dest) {
+                    self.time_limit_reached = true;
+                }
+                false
+            }
+        }
+    }
+
+    fn finish_step(&mut self, result: Option<EventEntry<SysEvent<M>>>) -> Option<ProcessedEvent<M>> {
+        if let (Some(filter), Some(entry)) = (&self.trace_filter, &result) {
+            match entry.event() {
+                SysEvent::MessageSend { msg, src, dest } if filter(msg) => {
+                    self.log(Some(&src.to()), LogKind::MessageSent { dest: dest.to(), msg: format!("{:?}", msg) });
+                }
+                SysEvent::MessageReceive { msg, src, dest } if filter(msg) => {
+                    self.log(Some(&dest.to()), LogKind::MessageReceived { src: src.to(), msg: format!("{:?}", msg) });
+                }
+                _ => {}
+            }
+        }
+        if let (Some(delivered), Some(entry)) = (self.delivered.as_mut(), &result) {
+            if let SysEvent::MessageReceive { msg, dest, .. } = entry.event() {
+                if self.nodes.contains_key(&dest.to()) && !self.crashed_nodes.contains(&dest.to()) {
+                    delivered.push(msg.clone());
+                }
+            }
+        }
+        if let Some(entry) = &result {
+            if let SysEvent::TimerFired { name, owner } = entry.event() {
+                if let Some(node) = self.nodes.get(&owner.to()) {
+                    node.borrow_mut().timer_fired(&entry.dest().to(), name, entry.id());
+                }
+            }
+        }
+        if let (Some((every, f)), Some(_)) = (self.progress.as_mut(), &result) {
+            let count = self.sim.processed_count();
+            if count.is_multiple_of(*every) {
+                f(count, self.sim.time());
+            }
+        }
+        let actions: Vec<ControlAction> = self.control.borrow_mut().actions.drain(..).collect();
+        for action in actions {
+            self.apply_control_action(action);
+        }
+        let crashes = self.net.borrow_mut().take_triggered_crashes();
+        for node_id in crashes {
+            if !self.node_is_crashed(&node_id) {
+                self.crash_node(&node_id);
+            }
+        }
+        if let Some(entry) = &result {
+            for (name, invariant) in &self.invariants {
+                if let Err(e) = invariant(self) {
+                    panic!("Invariant '{}' violated at {:.3} after {} -> {} {:?}: {}",
+                           name, entry.time(), entry.src(), entry.dest(), entry.event(), e);
+                }
+            }
+        }
+        result.map(|e| ProcessedEvent {
+            id: e.id(),
+            time: e.time(),
+            src: e.src().clone(),
+            dest: e.dest().clone(),
+            event: e.event().clone(),
+        })
+    }
+
+    pub fn steps(&mut self, step_count: u32) {
+        for _i in 0..step_count {
+            self.step();
+        }
+    }
+
+    pub fn step_until_no_events(&mut self) {
+        while self.step() {}
+    }
+
+    pub fn step_until_no_events_bounded(&mut self, max_steps: u64) -> Result<(), TooManyStepsError> {
+        for _i in 0..max_steps {
+            if !self.step() {
+                return Ok(());
+            }
+        }
+        let pending_events = self.sim.pending_events().len();
+        if pending_events == 0 {
+            Ok(())
+        } else {
+            Err(TooManyStepsError { max_steps, time: self.sim.time(), pending_events })
+        }
+    }
+
+    /// Processes all events scheduled at or before `time` and advances the clock to `time`.
+    pub fn run_until(&mut self, time: f64) {
+        while let Some(next_time) = self.sim.next_event_time() {
+            if next_time > time || !self.step() {
+                break;
+            }
+        }
+        self.sim.advance_clock(time.min(self.time_limit.unwrap_or(f64::INFINITY)));
+    }
+
+    pub fn step_while<F: FnMut(&SysEvent<M>) -> bool>(&mut self, mut f: F) {
+        while self.step() {
+            if !self.sim.pending_events().iter().any(|e| f(e.event())) {
+                return;
+            }
+        }
+    }
+
+    /// Processes events until `cond` holds for the system.
+    /// Returns false if the events ran out before that.
+    pub fn step_until<F: FnMut(&System<M>) -> bool>(&mut self, mut cond: F) -> bool {
+        while !cond(self) {
+            if !self.step() {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn get_local_events(&self, node_id: &str) -> Vec<LocalEvent<M>> {
+        self.try_get_local_events(node_id).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_local_events(&self, node_id: &str) -> Result<Vec<LocalEvent<M>>, UnknownNodeError> {
+        Ok(self.node_actor(node_id)?.borrow().get_local_events())
+    }
+
+    /// Returns the node's local events and clears its buffer,
+    /// unlike `get_local_events` which leaves the buffer intact.
+    pub fn take_local_events(&mut self, node_id: &str) -> Vec<LocalEvent<M>> {
+        self.try_take_local_events(node_id).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_take_local_events(&mut self, node_id: &str) -> Result<Vec<LocalEvent<M>>, UnknownNodeError> {
+        Ok(self.node_actor(node_id)?.borrow_mut().take_local_events())
+    }
+
+    pub fn assert_quiescent(&self) {
+        let pending = self.sim.pending_events();
+        let undelivered = self.sim.undelivered_events();
+        if pending.is_empty() && undelivered.is_empty() {
+            return;
+        }
+        let mut report = format!("System is not quiescent: {} pending and {} undelivered events",
+                                 pending.len(), undelivered.len());
+        for e in pending.iter().take(5) {
+            report.push_str(&format!("\n  pending {:>9.3} {} -> {} {:?}", e.time(), e.src(), e.dest(), e.event()));
+        }
+        for e in undelivered.iter().take(5) {
+            report.push_str(&format!("\n  undelivered {:>9.3} {} -> {} {:?}", e.time(), e.src(), e.dest(), e.event()));
+        }
+        panic!("{}", report);
+    }
+
+    /// Returns true if there are no pending events, but some alive node reports
+    /// that it is not done via `Node::is_done`, i.e. the system is stuck rather than finished.
+    pub fn is_deadlocked(&self) -> bool {
+        self.sim.pending_events().is_empty()
+            && self.node_impls.iter().any(|(id, node)| !self.node_is_crashed(id) && !node.borrow().is_done())
+    }
+
+    /// Builds the happens-before graph of events handled by nodes since `record_trace`.
+    pub fn causal_graph(&self) -> CausalGraph {
+        let history = self.sim.history();
+        let by_id: HashMap<u64, &EventEntry<SysEvent<M>>> = history.iter().map(|e| (e.id(), e)).collect();
+        let mut events = Vec::new();
+        let mut edges = Vec::new();
+        let mut last_on_node: HashMap<String, u64> = HashMap::new();
+        for e in history {
+            let node = e.dest().to();
+            if !self.nodes.contains_key(&node) || matches!(e.event(), SysEvent::Control { .. }) {
+                continue;
+            }
+            if let Some(prev) = last_on_node.insert(node.clone(), e.id()) {
+                edges.push((prev, e.id()));
+            }
+            if let SysEvent::MessageReceive { .. } = e.event() {
+                // follow the causes back through the network to the event which sent the message
+                let mut cause = e.cause();
+                while let Some(id) = cause {
+                    match by_id.get(&id) {
+                        Some(c) if self.nodes.contains_key(&c.dest().to()) => {
+                            edges.push((id, e.id()));
+                            break;
+                        }
+                        Some(c) => cause = c.cause(),
+                        None => break,
+                    }
+                }
+            }
+            events.push(CausalEvent { id: e.id(), time: e.time(), node, event: format!("{:?}", e.event()) });
+        }
+        CausalGraph { events, edges }
+    }
+
+    /// Matches replies sent with `Context::reply` to their requests among the events recorded
+    /// since `record_trace`. Returns (request `MessageSend` id, reply `MessageReceive` id) pairs.
+    pub fn reply_pairs(&self) -> Vec<(u64, u64)> {
+        let history = self.sim.history();
+        let by_id: HashMap<u64, &EventEntry<SysEvent<M>>> = history.iter().map(|e| (e.id(), e)).collect();
+        let replies = self.replies.borrow();
+        let cause_of = |id: u64| by_id.get(&id).and_then(|e| e.cause());
+        let mut pairs = Vec::new();
+        for e in history {
+            if !matches!(e.event(), SysEvent::MessageReceive { .. }) {
+                continue;
+            }
+            // a reply passes through the network unless it is delivered directly to the node itself
+            let reply_send = if replies.contains(&e.id()) {
+                Some(e.id())
+            } else {
+                e.cause().filter(|cause| replies.contains(cause))
+            };
+            // reply send <- handled request receive <- request send
+            if let Some(request) = reply_send.and_then(cause_of).and_then(cause_of) {
+                pairs.push((request, e.id()));
+            }
+        }
+        pairs
+    }
+
+    /// Starts retaining messages delivered to alive nodes for `any_delivered` and `count_delivered`.
+    pub fn record_deliveries(&mut self) {
+        if self.delivered.is_none() {
+            self.delivered = Some(Vec::new());
+        }
+    }
+
+    pub fn any_delivered<F: Fn(&M) -> bool>(&self, f: F) -> bool {
+        self.delivered_messages().iter().any(f)
+    }
+
+    pub fn count_delivered<F: Fn(&M) -> bool>(&self, f: F) -> usize {
+        self.delivered_messages().iter().filter(|msg| f(msg)).count()
+    }
+
+    fn delivered_messages(&self) -> &[M] {
+        self.delivered.as_deref().expect("Deliveries are not recorded, call record_deliveries first")
+    }
+
+    /// Folds `f` over the nodes in the order they were added, skipping crashed nodes
+    /// unless `include_crashed` is set.
+    pub fn fold_nodes<T, F>(&self, init: T, include_crashed: bool, f: F) -> T
+        where F: Fn(T, &str, &dyn Node<M>) -> T
+    {
+        self.node_ids.iter()
+            .filter(|id| include_crashed || !self.node_is_crashed(id))
+            .fold(init, |acc, id| f(acc, id, &*self.node_impls[id].borrow()))
+    }
+
+    /// Panics if two alive nodes hold different values, as extracted by `extract`.
+    /// Nodes for which `extract` returns None (e.g. undecided ones) are ignored.
+    pub fn assert_agreement<T, F>(&self, extract: F)
+        where T: Eq + Debug, F: Fn(&dyn Node<M>) -> Option<T>
+    {
+        let values: Vec<(&String, T)> = self.node_ids.iter()
+            .filter(|id| !self.node_is_crashed(id))
+            .filter_map(|id| extract(&*self.node_impls[id].borrow()).map(|value| (id, value)))
+            .collect();
+        if values.iter().all(|(_, value)| *value == values[0].1) {
+            return;
+        }
+        let mut report = "Nodes disagree:".to_string();
+        for (id, value) in &values {
+            report.push_str(&format!("\n  {}: {:?}", id, value));
+        }
+        panic!("{}", report);
+    }
+
+    /// Starts retaining messages dropped by the network and events discarded
+    /// because their destination was crashed, for `undelivered_events`.
+    pub fn record_undelivered(&mut self) {
+        self.net.borrow_mut().record_dropped_messages();
+        self.sim.record_discarded_events();
+    }
+
+    /// Lists messages dropped by the network and events which were not processed because
+    /// their destination was crashed or did not exist, ordered by time.
+    /// Only events since `record_undelivered` are included, events sent to unknown nodes are always kept.
+    pub fn undelivered_events(&self) -> Vec<UndeliveredEvent<M>> {
+        const NOT_RECORDED: &str = "Undelivered events are not recorded, call record_undelivered first";
+        let from_entry = |e: &EventEntry<SysEvent<M>>, reason: UndeliveryReason| UndeliveredEvent {
+            time: e.time(),
+            src: e.src().clone(),
+            dest: e.dest().clone(),
+            event: e.event().clone(),
+            reason,
+        };
+        let mut events: Vec<UndeliveredEvent<M>> = self.net.borrow().get_dropped_messages().expect(NOT_RECORDED).iter()
+            .map(|d| UndeliveredEvent {
+                time: d.time,
+                src: ActorId::from(&d.src),
+                dest: ActorId::from(&d.dest),
+                event: SysEvent::MessageSend {
+                    msg: d.msg.clone(),
+                    src: ActorId::from(&d.src),
+                    dest: ActorId::from(&d.dest),
+                },
+                reason: UndeliveryReason::Dropped(d.reason.clone()),
+            })
+            .collect();
+        events.extend(self.sim.discarded_events().expect(NOT_RECORDED).iter().map(|e| from_entry(e, UndeliveryReason::DestinationCrashed)));
+        events.extend(self.sim.undelivered_events().iter().map(|e| from_entry(e, UndeliveryReason::UnknownDestination)));
+        events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        events
+    }
+
+    pub fn count_undelivered_events(&mut self) -> usize {
+        self.sim.read_undelivered_events().len()
+    }
+
+    /// Saves the event queue, clock, RNG state, network configuration and node states,
+    /// so that the run can later be continued from this point with `restore`.
+    /// Pending events are copied, which relies on messages being `Clone`.
+    /// Node state is captured via `Node::clone_state`, nodes which don't implement it
+    /// keep their current state on restore. Network hooks (delay, tap, etc) are shared
+    /// with the snapshot rather than copied, invariants are not part of the snapshot.
+    pub fn snapshot(&self) -> Snapshot<M> {
+        let nodes = self.nodes.iter()
+            .map(|(id, actor)| (id.clone(), actor.borrow().snapshot()))
+            .collect();
+        Snapshot {
+            sim: self.sim.snapshot(),
+            net: self.net.borrow().clone(),
+            nodes,
+            node_impls: self.node_impls.clone(),
+            node_ids: self.node_ids.clone(),
+            crashed_nodes: self.crashed_nodes.clone(),
+            crash_history: self.crash_history.clone(),
+            replies: self.replies.borrow().clone(),
+            delivered: self.delivered.clone(),
+            clients: self.clients.borrow().clone(),
+        }
+    }
+
+    /// Creates an independent copy of the system, e.g. to run two different schedules
+    /// from the same point. Every node must implement `Node::clone_node`.
+    /// The fork's RNG is seeded from the next value of the parent's RNG, so forks made
+    /// at the same point make the same random decisions, but differ from the parent.
+    /// Invariants, the logger, message tracing, the progress callback and the adversary are not copied,
+    /// while network hooks and the timer observer are shared with the parent.
+    pub fn fork(&self) -> System<M> {
+        self.fork_with_sim(self.sim.fork())
+    }
+
+    /// Returns the next `n` events that would be processed, including the network's delay,
+    /// drop and duplication decisions, by running a copy of the system ahead (so every node
+    /// must implement `Node::clone_node`). The system itself is not changed.
+    /// This is a projection: it assumes no faults or other calls on the system in the meantime,
+    /// and it ignores the adversary. Nothing is logged or printed by the system while projecting,
+    /// but output printed by node code itself is not suppressed.
+    /// Delay functions (`set_delay_fn`, `set_delay_fn_msg`) are shared with the system rather than
+    /// copied, so stateful ones advance during the projection and may then return different delays.
+    pub fn peek_schedule(&self, n: usize) -> Vec<ProcessedEvent<M>> {
+        let mut fork = self.fork_with_sim(self.sim.snapshot());
+        fork.set_logger(Box::new(|_| {}));
+        fork.net.borrow_mut().set_tap(None);
+        for actor in fork.nodes.values() {
+            actor.borrow_mut().set_timer_observer(None);
+        }
+        let mut events = Vec::new();
+        while events.len() < n {
+            match fork.step_detailed() {
+                Some(e) => events.push(e),
+                None => break,
+            }
+        }
+        events
+    }
+
+    fn fork_with_sim(&self, sim: Simulation<SysEvent<M>>) -> System<M> {
+        let mut fork = System::with_sim(sim);
+        *fork.net.borrow_mut() = self.net.borrow().clone();
+        fork.net.borrow_mut().set_logger(Some(fork.logger.clone()));
+        *fork.replies.borrow_mut() = self.replies.borrow().clone();
+        fork.loopback_mode = self.loopback_mode;
+        fork.min_timer_delay = self.min_timer_delay;
+        fork.timer_observer = self.timer_observer.clone();
+        for id in &self.node_ids {
+            let node = self.node_impls[id].borrow().clone_node()
+                .unwrap_or_else(|| panic!("Node {} does not implement clone_node", id));
+            let actor = Rc::new(RefCell::new(self.nodes[id].borrow().fork(node.clone())));
+            actor.borrow_mut().set_reply_registry(Some(fork.replies.clone()));
+            actor.borrow_mut().set_members(Some(fork.members.clone()));
+            actor.borrow_mut().set_client_registry(Some(fork.clients.clone()));
+            actor.borrow_mut().set_logger(Some(fork.logger.clone()));
+            fork.sim.add_actor(id, actor.clone());
+            fork.nodes.insert(id.clone(), actor);
+            fork.node_impls.insert(id.clone(), node);
+        }
+        fork.node_ids = self.node_ids.clone();
+        fork.crashed_nodes = self.crashed_nodes.clone();
+        fork.update_members();
+        fork.crash_history = self.crash_history.clone();
+        fork.delivered = self.delivered.clone();
+        fork.time_limit = self.time_limit;
+        fork.time_limit_reached = self.time_limit_reached;
+        fork.client_nodes = self.client_nodes.clone();
+        fork.init_timers = self.init_timers.clone();
+        *fork.clients.borrow_mut() = self.clients.borrow().clone();
+        fork
+    }
+
+    /// Returns the system to the state saved by `snapshot`.
+    /// The same snapshot can be restored multiple times to explore different runs.
+    pub fn restore(&mut self, snapshot: &Snapshot<M>) {
+        self.sim.restore(&snapshot.sim);
+        *self.net.borrow_mut() = snapshot.net.clone();
+        self.net.borrow_mut().set_logger(Some(self.logger.clone()));
+        let removed: Vec<String> = self.nodes.keys()
+            .filter(|id| !snapshot.nodes.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in removed {
+            self.sim.remove_actor(&id);
+            self.nodes.remove(&id);
+            self.node_impls.remove(&id);
+        }
+        for (id, node_snapshot) in &snapshot.nodes {
+            let node = &snapshot.node_impls[id];
+            if !self.nodes.contains_key(id) || !Rc::ptr_eq(&self.node_impls[id], node) {
+                let actor = self.new_node_actor(node.clone());
+                self.sim.add_actor(id, actor.clone());
+                self.nodes.insert(id.clone(), actor);
+                self.node_impls.insert(id.clone(), node.clone());
+            }
+            self.nodes[id].borrow_mut().restore(node_snapshot);
+        }
+        self.node_ids = snapshot.node_ids.clone();
+        self.crashed_nodes = snapshot.crashed_nodes.clone();
+        self.update_members();
+        self.crash_history = snapshot.crash_history.clone();
+        *self.replies.borrow_mut() = snapshot.replies.clone();
+        self.delivered = snapshot.delivered.clone();
+        *self.clients.borrow_mut() = snapshot.clients.clone();
+        for name in self.client_nodes.keys() {
+            self.clients.borrow_mut().entry(name.clone()).or_default();
+        }
+        self.control.borrow_mut().actions.clear();
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<M: Debug + Clone + Serialize + 'static> System<M> {
+    /// Serializes events processed since `record_trace` was called, which must be done
+    /// before the run to capture all of them. Panics if the trace is not recorded.
+    /// A `MessageReceive` refers to its `MessageSend` via the `cause` field.
+    /// A reply sent with `Context::reply` refers to the `MessageSend` of its request via `reply_to`.
+    pub fn export_trace_json(&self) -> String {
+        assert!(self.sim.is_recording_trace(), "Trace is not recorded, call record_trace first");
+        let requests: HashMap<u64, u64> = self.reply_pairs().into_iter()
+            .map(|(request, reply)| (reply, request))
+            .collect();
+        let events: Vec<_> = self.sim.history().iter()
+            .map(|e| json!({
+                "id": e.id(),
+                "time": e.time(),
+                "src": e.src(),
+                "dest": e.dest(),
+                "cause": e.cause(),
+                "reply_to": requests.get(&e.id()),
+                "event": e.event(),
+            }))
+            .collect();
+        json!({
+            "version": TRACE_SCHEMA_VERSION,
+            "events": events,
+        }).to_string()
+    }
+}
+
+impl<M: Debug + Clone + Hash + Eq + 'static> System<M> {
+    /// When enabled, the network delivers each distinct message at most once per link.
+    /// Messages are compared as sent, before corruption. A repeated message is dropped
+    /// (reason "duplicate") before it takes up link bandwidth or a delivery slot,
+    /// and duplicates produced by `set_dupl_rate` are never created.
+    pub fn set_dedup(&mut self, enabled: bool) {
+        self.net.borrow_mut().set_dedup(enabled);
+    }
+}
+
+impl<M: Debug + Clone + Ttl + 'static> System<M> {
+    /// Makes the network decrement the TTL of each message it handles
+    /// and drop messages whose TTL has already reached zero.
+    pub fn enable_ttl(&mut self) {
+        self.net.borrow_mut().set_hop_fn(Some(Box::new(|msg: &M| msg.hop())));
+    }
+}
+
+impl<M: Debug + Clone + Prioritized + 'static> System<M> {
+    /// Makes messages waiting in a link's send queue (see `set_link_window`)
+    /// leave it in order of priority rather than in order of sending.
+    /// Has no effect on links without a window: messages there never wait,
+    /// and their delivery times don't depend on priority.
+    /// Panics if no link has a window, so set the windows first.
+    pub fn enable_priorities(&mut self) {
+        assert!(self.net.borrow().has_link_windows(),
+                "Priorities only apply to links with a window, call set_link_window first");
+        self.net.borrow_mut().set_priority_fn(Some(Box::new(|msg: &M| msg.priority())));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidConfigError {
+    pub reason: String,
+}
+
+impl std::fmt::Display for InvalidConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid system config: {}", self.reason)
+    }
+}
+
+impl std::error::Error for InvalidConfigError {}
+
+/// Configures a `System` with chained calls, settings are validated by `build`.
+pub struct SystemBuilder<M: Debug + Clone> {
+    seed: Option<u64>,
+    delays: (f64, f64),
+    drop_rate: f64,
+    dupl_rate: f64,
+    fifo: bool,
+    nodes: Vec<Rc<RefCell<dyn Node<M>>>>,
+}
+
+impl<M: Debug + Clone + 'static> Default for SystemBuilder<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Debug + Clone + 'static> SystemBuilder<M> {
+    pub fn new() -> Self {
+        Self {
+            seed: None,
+            delays: (1., 1.),
+            drop_rate: 0.,
+            dupl_rate: 0.,
+            fifo: false,
+            nodes: Vec::new(),
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn delay(mut self, delay: f64) -> Self {
+        self.delays = (delay, delay);
+        self
+    }
+
+    pub fn delays(mut self, min_delay: f64, max_delay: f64) -> Self {
+        self.delays = (min_delay, max_delay);
+        self
+    }
+
+    pub fn drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    pub fn dupl_rate(mut self, dupl_rate: f64) -> Self {
+        self.dupl_rate = dupl_rate;
+        self
+    }
+
+    pub fn fifo(mut self, enabled: bool) -> Self {
+        self.fifo = enabled;
+        self
+    }
+
+    pub fn node(mut self, node: Rc<RefCell<dyn Node<M>>>) -> Self {
+        self.nodes.push(node);
+        self
+    }
+
+    /// Creates the system, with a random seed unless `seed` was given.
+    pub fn build(self) -> Result<System<M>, InvalidConfigError> {
+        let invalid = |reason: String| Err(InvalidConfigError { reason });
+        let (min_delay, max_delay) = self.delays;
+        if min_delay < 0. || min_delay > max_delay {
+            return invalid(format!("delay range {}..{} is invalid", min_delay, max_delay));
+        }
+        for (name, rate) in [("drop", self.drop_rate), ("duplication", self.dupl_rate)] {
+            if !(0. ..=1.).contains(&rate) {
+                return invalid(format!("{} rate {} is not within 0..1", name, rate));
+            }
+        }
+        let mut ids = HashSet::new();
+        for node in &self.nodes {
+            let id = node.borrow().id().clone();
+            if !ids.insert(id.clone()) {
+                return invalid(format!("node id '{}' is used more than once", id));
+            }
+        }
+        let mut system = match self.seed {
+            Some(seed) => System::with_seed(seed),
+            None => System::new(),
+        };
+        system.set_delays(min_delay, max_delay);
+        system.set_drop_rate(self.drop_rate);
+        system.set_dupl_rate(self.dupl_rate);
+        system.set_fifo(self.fifo);
+        for node in self.nodes {
+            system.add_node(node);
+        }
+        Ok(system)
+    }
+}
@@ -10,6 +10,12 @@ pub struct TestSuite<T> {
     tests: Vec<Test<T>>
 }
 
+impl<T> Default for TestSuite<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> TestSuite<T> {
     pub fn new() -> Self {
         Self { tests: Vec::new() }
@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use dslib::node::{Context, Node};
+use dslib::system::System;
+
+const QUORUM: usize = 3;
+
+#[derive(Debug, Clone)]
+struct Vote {
+    value: u64,
+    to: String,
+}
+
+// Broadcasts its value and decides once a quorum of nodes voted for the same value
+struct Voter {
+    id: String,
+    peers: Vec<String>,
+    value: u64,
+    votes: HashMap<String, u64>,
+    decided: Option<u64>,
+}
+
+impl Voter {
+    fn new(id: &str, peers: &[&str], value: u64) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self {
+            id: id.to_string(),
+            peers: peers.iter().filter(|p| **p != id).map(|p| p.to_string()).collect(),
+            value,
+            votes: HashMap::new(),
+            decided: None,
+        }))
+    }
+
+    fn add_vote(&mut self, from: String, value: u64) {
+        self.votes.insert(from, value);
+        if self.decided.is_none() && self.votes.values().filter(|v| **v == value).count() >= QUORUM {
+            self.decided = Some(value);
+        }
+    }
+}
+
+impl Node<Vote> for Voter {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, msg: Vote, from: String, _ctx: &mut Context<Vote>) {
+        self.add_vote(from, msg.value);
+    }
+
+    fn on_local_message(&mut self, _msg: Vote, _ctx: &mut Context<Vote>) {}
+
+    fn on_timer(&mut self, _timer: String, ctx: &mut Context<Vote>) {
+        self.add_vote(self.id.clone(), self.value);
+        for peer in &self.peers {
+            ctx.send(Vote { value: self.value, to: peer.clone() }, peer);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.votes.clear();
+        self.decided = None;
+    }
+}
+
+#[test]
+fn quorum_agrees_despite_equivocating_node() {
+    let ids = ["n0", "n1", "n2", "n3"];
+    let mut sys = System::with_seed(1);
+    for id in ids {
+        sys.add_node(Voter::new(id, &ids, 1));
+    }
+    // n3 tells n0 one value and everyone else another one
+    sys.make_byzantine("n3", Box::new(|msg: &Vote| {
+        let value = if msg.to == "n0" { 0 } else { 2 };
+        vec![Vote { value, ..msg.clone() }]
+    }));
+    sys.step_until_no_events();
+
+    assert_eq!(sys.get_node::<Voter>("n0").votes["n3"], 0);
+    assert_eq!(sys.get_node::<Voter>("n1").votes["n3"], 2);
+    for id in &ids[..3] {
+        assert_eq!(sys.get_node::<Voter>(id).decided, Some(1));
+    }
+}
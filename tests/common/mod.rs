@@ -0,0 +1,41 @@
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use dslib::node::{Context, Node};
+
+// Node which records the messages it receives, messages are sent to it with `System::send`
+pub struct Inbox<M> {
+    id: String,
+    pub received: Vec<(f64, String, M)>,
+}
+
+impl<M: Debug + Clone + 'static> Inbox<M> {
+    pub fn new(id: &str) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self { id: id.to_string(), received: Vec::new() }))
+    }
+
+    pub fn messages(&self) -> Vec<M> {
+        self.received.iter().map(|(_, _, msg)| msg.clone()).collect()
+    }
+}
+
+impl<M: Debug + Clone + 'static> Node<M> for Inbox<M> {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, msg: M, from: String, ctx: &mut Context<M>) {
+        self.received.push((ctx.time(), from, msg));
+    }
+
+    fn on_local_message(&mut self, _msg: M, _ctx: &mut Context<M>) {}
+
+    fn on_timer(&mut self, _timer: String, _ctx: &mut Context<M>) {}
+
+    fn reset(&mut self) {
+        self.received.clear();
+    }
+}
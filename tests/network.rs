@@ -0,0 +1,291 @@
+mod common;
+
+use common::Inbox;
+use dslib::net::Prioritized;
+use dslib::system::{System, UndeliveryReason};
+
+fn system() -> System<u64> {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Inbox::<u64>::new("b"));
+    sys
+}
+
+fn receive_times(sys: &System<u64>, node_id: &str) -> Vec<f64> {
+    sys.get_node::<Inbox<u64>>(node_id).received.iter().map(|(time, _, _)| *time).collect()
+}
+
+#[test]
+fn flooded_slow_link_delivers_at_increasing_times() {
+    let mut sys = system();
+    sys.set_delays(0.5, 1.5);
+    sys.set_link_bandwidth("a", "b", 2.);
+    for i in 0..20 {
+        sys.send(i, "a", "b");
+    }
+    sys.step_until_no_events();
+
+    let times = receive_times(&sys, "b");
+    assert_eq!(times.len(), 20);
+    // each message occupies the link for half a second
+    assert!(times.windows(2).all(|w| w[1] - w[0] >= 0.5 - 1e-9), "{:?}", times);
+}
+
+#[test]
+fn corrupt_rate_one_transforms_every_message() {
+    let mut sys = system();
+    sys.set_corrupt_fn(Box::new(|msg| msg + 100));
+    sys.set_corrupt_rate(1.);
+    for i in 0..10 {
+        sys.send(i, "a", "b");
+    }
+    sys.step_until_no_events();
+
+    let mut received = sys.get_node::<Inbox<u64>>("b").messages();
+    received.sort_unstable();
+    assert_eq!(received, (100..110).collect::<Vec<u64>>());
+}
+
+#[test]
+#[should_panic(expected = "call set_corrupt_fn first")]
+fn corrupt_rate_requires_corrupt_fn() {
+    let mut sys = system();
+    sys.set_corrupt_rate(0.5);
+}
+
+#[test]
+fn heal_partition_keeps_links_disabled_outside_partition() {
+    let mut sys = system();
+    sys.disable_link("b", "a");
+    sys.make_partition(&["a"], &["b"]);
+    sys.send(1, "a", "b");
+    sys.send(2, "b", "a");
+    sys.step_until_no_events();
+
+    let drops = sys.heal_partition();
+    assert_eq!(drops.len(), 1);
+    assert_eq!(drops[0].msg, 1);
+
+    sys.send(3, "a", "b");
+    sys.send(4, "b", "a");
+    sys.step_until_no_events();
+    assert_eq!(sys.get_node::<Inbox<u64>>("b").messages(), vec![3]);
+    assert!(sys.get_node::<Inbox<u64>>("a").received.is_empty());
+    // drops after healing are not reported by the next heal
+    assert!(sys.heal_partition().is_empty());
+}
+
+#[test]
+fn message_waits_for_full_link_window() {
+    let mut sys = system();
+    sys.set_delays(1., 1.);
+    sys.set_link_window("a", "b", 2);
+    for i in 0..3 {
+        sys.send(i, "a", "b");
+    }
+    sys.step_until_no_events();
+
+    assert_eq!(sys.get_node::<Inbox<u64>>("b").messages(), vec![0, 1, 2]);
+    assert_eq!(receive_times(&sys, "b"), vec![1., 1., 2.]);
+}
+
+#[test]
+#[should_panic(expected = "at least one message in flight")]
+fn empty_link_window_is_rejected() {
+    let mut sys = system();
+    sys.set_link_window("a", "b", 0);
+}
+
+#[test]
+fn reset_network_removes_link_windows() {
+    let mut sys = system();
+    sys.set_delays(1., 1.);
+    sys.set_link_window("a", "b", 1);
+    sys.send(0, "a", "b");
+    sys.send(1, "a", "b");
+    sys.run_until(0.5);
+
+    let discarded = sys.reset_network();
+    assert_eq!(discarded.iter().map(|m| m.msg).collect::<Vec<_>>(), vec![1]);
+    sys.send(2, "a", "b");
+    sys.send(3, "a", "b");
+    sys.step_until_no_events();
+    assert_eq!(receive_times(&sys, "b"), vec![1., 1.5, 1.5]);
+}
+
+#[test]
+fn duplicates_follow_fifo_order() {
+    let mut sys = system();
+    sys.set_delays(0.5, 1.5);
+    sys.set_fifo(true);
+    sys.set_dupl_rate(1.);
+    sys.set_dupl_count(2, 2);
+    for i in 0..10 {
+        sys.send(i, "a", "b");
+    }
+    sys.step_until_no_events();
+
+    let received = sys.get_node::<Inbox<u64>>("b").messages();
+    assert_eq!(received, (0..10).flat_map(|i| vec![i, i]).collect::<Vec<u64>>());
+    let times = receive_times(&sys, "b");
+    assert!(times.windows(2).all(|w| w[0] <= w[1]), "{:?}", times);
+}
+
+#[test]
+fn duplicates_arrive_after_original() {
+    let mut sys = system();
+    sys.set_delays(0.5, 1.5);
+    sys.set_dupl_rate(1.);
+    sys.set_dupl_count(3, 3);
+    sys.send(0, "a", "b");
+    sys.step_until_no_events();
+
+    let times = receive_times(&sys, "b");
+    assert_eq!(times.len(), 3);
+    assert!(times.iter().all(|t| *t >= times[0]), "{:?}", times);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Traffic {
+    Data(u64),
+    Control,
+}
+
+impl Prioritized for Traffic {
+    fn priority(&self) -> i64 {
+        match self {
+            Traffic::Data(_) => 0,
+            Traffic::Control => 1,
+        }
+    }
+}
+
+#[test]
+fn control_message_overtakes_queued_data() {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Inbox::<Traffic>::new("a"));
+    sys.add_node(Inbox::<Traffic>::new("b"));
+    sys.set_delays(1., 1.);
+    sys.set_link_window("a", "b", 1);
+    sys.enable_priorities();
+    for i in 0..3 {
+        sys.send(Traffic::Data(i), "a", "b");
+    }
+    sys.send(Traffic::Control, "a", "b");
+    sys.step_until_no_events();
+
+    let received = sys.get_node::<Inbox<Traffic>>("b").messages();
+    assert_eq!(received, vec![Traffic::Data(0), Traffic::Control, Traffic::Data(1), Traffic::Data(2)]);
+}
+
+#[test]
+#[should_panic(expected = "call set_link_window first")]
+fn priorities_without_link_windows_are_rejected() {
+    let mut sys = System::<Traffic>::with_seed(1);
+    sys.enable_priorities();
+}
+
+#[test]
+fn undelivered_events_are_recorded_until_reset() {
+    let mut sys = system();
+    sys.record_undelivered();
+    sys.disable_link("a", "b");
+    sys.send(1, "a", "b");
+    sys.step_until_no_events();
+    sys.enable_link("a", "b");
+    sys.crash_node("b");
+    sys.send(2, "a", "b");
+    sys.step_until_no_events();
+
+    let reasons: Vec<_> = sys.undelivered_events().into_iter().map(|e| e.reason).collect();
+    assert!(matches!(reasons.as_slice(), [UndeliveryReason::Dropped(_), UndeliveryReason::DestinationCrashed]), "{:?}", reasons);
+    sys.reset_network();
+    assert!(sys.undelivered_events().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "call record_undelivered first")]
+fn undelivered_events_require_recording() {
+    let sys = system();
+    sys.undelivered_events();
+}
+
+#[test]
+#[should_panic(expected = "is not a permutation")]
+fn invalid_link_permutation_is_rejected() {
+    let mut sys = system();
+    sys.set_link_permutation("a", "b", vec![0, 2]);
+}
+
+#[test]
+fn messages_held_by_permutation_are_in_flight_until_reset() {
+    let mut sys = system();
+    sys.set_link_permutation("a", "b", vec![2, 1, 0]);
+    sys.send(0, "a", "b");
+    sys.send(1, "a", "b");
+    sys.step_until_no_events();
+    assert_eq!(sys.in_flight_count(), 2);
+    assert_eq!(sys.in_flight_between("a", "b"), 2);
+
+    let discarded = sys.reset_network();
+    assert_eq!(discarded.iter().map(|m| m.msg).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(sys.in_flight_count(), 0);
+    sys.send(2, "a", "b");
+    sys.step_until_no_events();
+    assert_eq!(sys.get_node::<Inbox<u64>>("b").messages(), vec![2]);
+}
+
+#[test]
+fn suppressed_duplicates_do_not_use_link_bandwidth() {
+    let mut sys = system();
+    sys.set_delays(1., 1.);
+    sys.set_link_bandwidth("a", "b", 1.);
+    sys.set_dedup(true);
+    for _ in 0..6 {
+        sys.send(7, "a", "b");
+    }
+    sys.send(8, "a", "b");
+    sys.step_until_no_events();
+
+    assert_eq!(sys.get_node::<Inbox<u64>>("b").messages(), vec![7, 8]);
+    assert_eq!(receive_times(&sys, "b"), vec![2., 3.]);
+    assert_eq!(sys.node_message_stats("a").dropped, 5);
+}
+
+#[test]
+fn reorder_window_of_one_swaps_adjacent_messages() {
+    let mut sys = system();
+    sys.set_delays(1., 1.);
+    sys.set_reorder_window(1);
+    for i in 0..20 {
+        sys.send(i, "a", "b");
+    }
+    sys.step_until_no_events();
+
+    let received = sys.get_node::<Inbox<u64>>("b").messages();
+    assert!(received.windows(2).any(|w| w[0] == w[1] + 1), "{:?}", received);
+    // no message is delivered before one sent two or more messages earlier
+    for (pos, msg) in received.iter().enumerate() {
+        assert!(received[pos + 1..].iter().all(|later| later + 1 >= *msg), "{:?}", received);
+    }
+}
+
+#[test]
+fn end_of_scheduled_outage_keeps_manually_disabled_link_down() {
+    let mut sys = system();
+    sys.schedule_link_down("a", "b", 1., 2.);
+    sys.run_until(1.5);
+    assert!(!sys.link_enabled("a", "b"));
+    sys.disable_link("a", "b");
+    sys.run_until(3.);
+    assert!(!sys.link_enabled("a", "b"));
+    sys.enable_link("a", "b");
+    assert!(sys.link_enabled("a", "b"));
+}
+
+#[test]
+#[should_panic(expected = "must not end before it starts")]
+fn outage_ending_before_start_is_rejected() {
+    let mut sys = system();
+    sys.schedule_link_down("a", "b", 2., 1.);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::Inbox;
+use dslib::node::{Context, LoopbackMode, Node};
+use dslib::system::{LogKind, System};
+
+// Node which sends its local messages to itself
+struct SelfSender {
+    id: String,
+    received: Vec<(f64, u64)>,
+}
+
+impl Node<u64> for SelfSender {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, msg: u64, _from: String, ctx: &mut Context<u64>) {
+        self.received.push((ctx.time(), msg));
+    }
+
+    fn on_local_message(&mut self, msg: u64, ctx: &mut Context<u64>) {
+        let id = self.id.clone();
+        ctx.send(msg, &id);
+    }
+
+    fn on_timer(&mut self, _timer: String, _ctx: &mut Context<u64>) {}
+
+    fn reset(&mut self) {
+        self.received.clear();
+    }
+}
+
+// Node which persists the number of messages it has received but not the messages themselves
+struct Counter {
+    id: String,
+    count: u64,
+    received: Vec<u64>,
+}
+
+impl Node<u64> for Counter {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, msg: u64, _from: String, ctx: &mut Context<u64>) {
+        self.count += 1;
+        self.received.push(msg);
+        ctx.persist();
+    }
+
+    fn on_local_message(&mut self, _msg: u64, _ctx: &mut Context<u64>) {}
+
+    fn on_timer(&mut self, _timer: String, _ctx: &mut Context<u64>) {}
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.received.clear();
+    }
+
+    fn persistent_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.count))
+    }
+
+    fn restore_persistent(&mut self, state: &dyn Any) {
+        self.count = *state.downcast_ref::<u64>().unwrap();
+    }
+}
+
+fn self_sender_system(mode: LoopbackMode) -> System<u64> {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Rc::new(RefCell::new(SelfSender { id: "a".to_string(), received: Vec::new() })));
+    sys.set_loopback_mode(mode);
+    sys.set_delays(1., 1.);
+    sys.disable_link("a", "a");
+    sys
+}
+
+#[test]
+fn node_added_mid_run_receives_messages() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.run_until(5.);
+
+    sys.add_node(Inbox::<u64>::new("b"));
+    sys.run_until(6.);
+    sys.send(1, "a", "b");
+    sys.step_until_no_events();
+
+    let received = &sys.get_node::<Inbox<u64>>("b").received;
+    assert_eq!(received.len(), 1);
+    let (time, from, msg) = &received[0];
+    assert!(*time >= 6., "{}", time);
+    assert_eq!((from.as_str(), *msg), ("a", 1));
+}
+
+#[test]
+fn direct_loopback_bypasses_network() {
+    let mut sys = self_sender_system(LoopbackMode::Direct);
+    sys.send_local(1, "a");
+    sys.step_until_no_events();
+
+    assert_eq!(sys.get_node::<SelfSender>("a").received, vec![(0., 1)]);
+}
+
+#[test]
+fn network_loopback_goes_through_network() {
+    let mut sys = self_sender_system(LoopbackMode::Network);
+    sys.send_local(1, "a");
+    sys.step_until_no_events();
+    assert!(sys.get_node::<SelfSender>("a").received.is_empty());
+
+    sys.enable_link("a", "a");
+    let sent_at = sys.time();
+    sys.send_local(2, "a");
+    sys.step_until_no_events();
+    // messages sent by nodes take 1 to reach the network, then the link delay
+    assert_eq!(sys.get_node::<SelfSender>("a").received, vec![(sent_at + 2., 2)]);
+}
+
+#[test]
+fn recovered_node_keeps_only_persisted_state() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Rc::new(RefCell::new(Counter { id: "b".to_string(), count: 0, received: Vec::new() })));
+    sys.send(1, "a", "b");
+    sys.send(2, "a", "b");
+    sys.step_until_no_events();
+
+    sys.crash_node("b");
+    sys.recover_node("b");
+    let node = sys.get_node::<Counter>("b");
+    assert_eq!(node.count, 2);
+    assert!(node.received.is_empty());
+}
+
+#[test]
+fn messages_sent_before_crash_are_lost_on_recovery() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Inbox::<u64>::new("b"));
+    sys.send(1, "a", "b");
+    sys.crash_node("a");
+    sys.recover_node("a");
+    sys.step_until_no_events();
+    assert!(sys.get_node::<Inbox<u64>>("b").received.is_empty());
+}
+
+#[test]
+fn messages_sent_by_removed_node_are_dropped() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Inbox::<u64>::new("b"));
+    sys.send(1, "a", "b");
+    sys.remove_node("a");
+    sys.step_until_no_events();
+    assert!(sys.get_node::<Inbox<u64>>("b").received.is_empty());
+}
+
+#[test]
+fn crashing_crashed_node_does_nothing() {
+    let mut sys = System::<u64>::with_seed(1);
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let logged = records.clone();
+    sys.set_logger(Box::new(move |record| logged.borrow_mut().push(record.clone())));
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.crash_node("a");
+    sys.crash_node("a");
+    let crashes = records.borrow().iter().filter(|r| matches!(r.kind, LogKind::NodeCrashed)).count();
+    assert_eq!(crashes, 1);
+}
+
+#[test]
+#[should_panic(expected = "unknown node id 'c'")]
+fn crash_of_unknown_node_is_rejected_when_scheduled() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.schedule_crash("c", 1.);
+}
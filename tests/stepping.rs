@@ -0,0 +1,215 @@
+mod common;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use common::Inbox;
+use dslib::node::{Context, Node};
+use dslib::system::{LogKind, System};
+
+// Node which answers every message with the next number, so two of them exchange messages forever
+#[derive(Clone)]
+struct Pinger {
+    id: String,
+    received: Vec<u64>,
+}
+
+impl Pinger {
+    fn new(id: &str) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self { id: id.to_string(), received: Vec::new() }))
+    }
+}
+
+impl Node<u64> for Pinger {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, msg: u64, from: String, ctx: &mut Context<u64>) {
+        self.received.push(msg);
+        ctx.send(msg + 1, &from);
+    }
+
+    fn on_local_message(&mut self, _msg: u64, _ctx: &mut Context<u64>) {}
+
+    fn on_timer(&mut self, _timer: String, _ctx: &mut Context<u64>) {}
+
+    fn clone_state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.received.clone()))
+    }
+
+    fn restore_state(&mut self, state: &dyn Any) {
+        self.received = state.downcast_ref::<Vec<u64>>().unwrap().clone();
+    }
+
+    fn clone_node(&self) -> Option<Rc<RefCell<dyn Node<u64>>>> {
+        Some(Rc::new(RefCell::new(self.clone())))
+    }
+}
+
+// Pair of pingers exchanging several messages over a lossy network with random delays
+fn ping_pong() -> System<u64> {
+    let mut sys = System::with_seed(3);
+    sys.set_delays(0.5, 1.5);
+    sys.set_drop_rate(0.1);
+    sys.set_dupl_rate(0.2);
+    sys.add_node(Pinger::new("a"));
+    sys.add_node(Pinger::new("b"));
+    for i in 0..5 {
+        sys.send(i * 100, "a", "b");
+    }
+    sys
+}
+
+fn run_steps(sys: &mut System<u64>, steps: usize) -> Vec<(f64, String, String, String)> {
+    (0..steps)
+        .filter_map(|_| sys.step_detailed())
+        .map(|e| (e.time, e.src.to(), e.dest.to(), format!("{:?}", e.event)))
+        .collect()
+}
+
+#[test]
+fn time_limit_is_told_apart_from_no_events() {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.set_time_limit(5.);
+    sys.step_until_no_events();
+    assert!(!sys.time_limit_reached());
+
+    sys.send_local_after(1, "a", 10.);
+    sys.step_until_no_events();
+    assert!(sys.time_limit_reached());
+
+    sys.clear_time_limit();
+    assert!(!sys.time_limit_reached());
+    sys.step_until_no_events();
+    assert!(!sys.time_limit_reached());
+    assert_eq!(sys.time(), 10.);
+}
+
+fn run_exchange(mut sys: System<u64>) -> System<u64> {
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Inbox::<u64>::new("b"));
+    for i in 0..30 {
+        sys.send(i, "a", "b");
+        sys.send(100 + i, "b", "a");
+    }
+    sys.step_until_no_events();
+    sys
+}
+
+#[test]
+fn replay_reproduces_drops_and_duplicates() {
+    let mut sys = System::with_seed(7);
+    sys.record_trace();
+    sys.set_drop_rate(0.3);
+    sys.set_dupl_rate(0.3);
+    let recorded = run_exchange(sys);
+    let log = |sys: &System<u64>, id: &str| sys.get_node::<Inbox<u64>>(id).received.clone();
+    // the recorded run must contain both drops and duplicates
+    let delivered = recorded.get_node::<Inbox<u64>>("b").messages();
+    let distinct: HashSet<u64> = delivered.iter().copied().collect();
+    assert!(distinct.len() < 30 && delivered.len() > distinct.len(), "{:?}", delivered);
+
+    let replayed = run_exchange(System::replay(recorded.get_trace()));
+    assert!(replayed.replay_divergence().is_none());
+    for id in ["a", "b"] {
+        assert_eq!(log(&replayed, id), log(&recorded, id), "deliveries to {} differ", id);
+    }
+}
+
+#[test]
+fn replay_divergence_is_reported() {
+    let mut sys = System::with_seed(7);
+    sys.record_trace();
+    let recorded = run_exchange(sys);
+
+    let mut replayed = System::<u64>::replay(recorded.get_trace());
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let logged = records.clone();
+    replayed.set_logger(Box::new(move |record| logged.borrow_mut().push(record.clone())));
+    replayed.add_node(Inbox::<u64>::new("a"));
+    replayed.add_node(Inbox::<u64>::new("b"));
+    replayed.send(0, "a", "b");
+    replayed.step_until_no_events();
+
+    assert!(replayed.replay_divergence().is_some());
+    assert!(records.borrow().iter().any(|r| matches!(r.kind, LogKind::ReplayDiverged { .. })));
+}
+
+#[test]
+fn step_messages_only_stops_after_max_steps() {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Pinger::new("a"));
+    sys.add_node(Pinger::new("b"));
+    sys.add_node(Inbox::<u64>::new("c"));
+    sys.send(0, "a", "c");
+    assert_eq!(sys.step_messages_only(100).unwrap(), 2);
+    assert_eq!(sys.get_node::<Inbox<u64>>("c").messages(), vec![0]);
+
+    sys.send(0, "a", "b");
+    let err = sys.step_messages_only(100).unwrap_err();
+    assert_eq!(err.max_steps, 100);
+    assert!(err.pending_events > 0);
+    assert_eq!(sys.get_node::<Pinger>("b").received.len(), 25);
+}
+
+#[test]
+fn network_and_node_output_goes_to_logger_but_not_from_projection() {
+    let mut sys = System::with_seed(1);
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let logged = records.clone();
+    sys.set_logger(Box::new(move |record| logged.borrow_mut().push(record.clone())));
+    sys.add_node(Pinger::new("a"));
+    sys.add_node(Pinger::new("b"));
+    sys.drop_next("b", "a", 1);
+    sys.send(0, "a", "b");
+
+    let projected = sys.peek_schedule(10);
+    assert!(!projected.is_empty());
+    assert!(records.borrow().is_empty());
+
+    sys.step_until_no_events();
+    let kinds: Vec<LogKind> = records.borrow().iter().map(|r| r.kind.clone()).collect();
+    assert!(matches!(kinds.as_slice(), [
+        LogKind::TimerFired { .. }, LogKind::TimerFired { .. }, LogKind::Received { .. }, LogKind::Sent { .. }, LogKind::MessageDropped { .. }
+    ]), "{:?}", kinds);
+}
+
+#[test]
+fn restore_reproduces_subsequent_events() {
+    let mut sys = ping_pong();
+    sys.set_logger(Box::new(|_| {}));
+    run_steps(&mut sys, 20);
+    let snapshot = sys.snapshot();
+    let received = sys.get_node::<Pinger>("b").received.clone();
+
+    let first = run_steps(&mut sys, 50);
+    sys.restore(&snapshot);
+    assert_eq!(sys.get_node::<Pinger>("b").received, received);
+    let second = run_steps(&mut sys, 50);
+    assert_eq!(first.len(), 50);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn fork_leaves_parent_untouched() {
+    let mut sys = ping_pong();
+    let mut untouched = ping_pong();
+    sys.set_logger(Box::new(|_| {}));
+    untouched.set_logger(Box::new(|_| {}));
+    assert_eq!(run_steps(&mut sys, 20), run_steps(&mut untouched, 20));
+
+    let mut fork = sys.fork();
+    fork.set_logger(Box::new(|_| {}));
+    fork.crash_node("a");
+    run_steps(&mut fork, 50);
+
+    assert_eq!(sys.pending_events().len(), untouched.pending_events().len());
+    assert_eq!(sys.get_node::<Pinger>("b").received, untouched.get_node::<Pinger>("b").received);
+    assert!(!sys.node_is_crashed("a"));
+    assert_eq!(run_steps(&mut sys, 50), run_steps(&mut untouched, 50));
+    assert_eq!(sys.get_node::<Pinger>("a").received, untouched.get_node::<Pinger>("a").received);
+}
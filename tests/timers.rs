@@ -0,0 +1,118 @@
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::Inbox;
+use dslib::node::{Context, Node, TimerEvent};
+use dslib::system::{LogKind, System};
+
+// Node which sets a timer with the delay given in a local message, in milliseconds
+struct Sleeper {
+    id: String,
+}
+
+impl Node<u64> for Sleeper {
+    fn id(&self) -> &String {
+        &self.id
+    }
+
+    fn on_message(&mut self, _msg: u64, _from: String, _ctx: &mut Context<u64>) {}
+
+    fn on_local_message(&mut self, delay: u64, ctx: &mut Context<u64>) {
+        ctx.set_timer("sleep", delay as f64 / 1000.);
+    }
+
+    fn on_timer(&mut self, _timer: String, _ctx: &mut Context<u64>) {}
+
+    fn reset(&mut self) {}
+}
+
+fn system() -> (System<u64>, Rc<RefCell<Vec<TimerEvent>>>) {
+    let mut sys = System::with_seed(1);
+    sys.add_node(Inbox::<u64>::new("a"));
+    sys.add_node(Inbox::<u64>::new("b"));
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let observed = events.clone();
+    sys.set_timer_observer(Box::new(move |e| observed.borrow_mut().push(e.clone())));
+    (sys, events)
+}
+
+fn cancellations(events: &RefCell<Vec<TimerEvent>>) -> Vec<String> {
+    events.borrow().iter().filter_map(|e| match e {
+        TimerEvent::Cancelled { node, name, .. } => Some(format!("{}:{}", node, name)),
+        _ => None,
+    }).collect()
+}
+
+#[test]
+fn fired_timer_is_forgotten_by_its_owner() {
+    let (mut sys, events) = system();
+    sys.add_timer_for("a", "b", "t", 1.);
+    sys.step_until_no_events();
+
+    // the timer owned by `a` has already fired, so there is nothing to cancel
+    sys.cancel_timer_for("a", "b", "t");
+    assert!(cancellations(&events).is_empty());
+}
+
+#[test]
+fn timer_fired_on_other_node_keeps_its_own_timer() {
+    let (mut sys, events) = system();
+    sys.add_periodic_timer("b", "t", 10.);
+    sys.add_timer_for("a", "b", "t", 1.);
+    sys.run_until(5.);
+
+    sys.cancel_timer("b", "t");
+    assert_eq!(cancellations(&events), vec!["b:t"]);
+}
+
+#[test]
+fn replaced_timer_is_cancelled() {
+    let (mut sys, events) = system();
+    sys.add_periodic_timer("a", "t", 10.);
+    sys.add_periodic_timer("a", "t", 5.);
+    sys.add_timer_for("a", "b", "u", 1.);
+    sys.add_timer_for("a", "b", "u", 2.);
+    assert_eq!(cancellations(&events), vec!["a:t", "b:u"]);
+}
+
+#[test]
+fn crash_cancels_timers_of_node() {
+    let (mut sys, events) = system();
+    sys.run_until(0.5);
+    sys.add_periodic_timer("a", "t", 10.);
+    sys.add_timer_for("a", "b", "u", 1.);
+    sys.crash_node("a");
+    assert_eq!(cancellations(&events), vec!["a:t", "b:u"]);
+
+    sys.recover_node("a");
+    sys.run_until(20.);
+    let fired = events.borrow().iter().filter(|e| matches!(e, TimerEvent::Fired { name, .. } if name != "init")).count();
+    assert_eq!(fired, 0);
+}
+
+#[test]
+fn node_timer_clamp_is_logged() {
+    let mut sys = System::<u64>::with_seed(1);
+    sys.add_node(Rc::new(RefCell::new(Sleeper { id: "a".to_string() })));
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let logged = records.clone();
+    sys.set_logger(Box::new(move |record| logged.borrow_mut().push(record.clone())));
+    sys.set_min_timer_delay(0.1);
+    sys.send_local(1, "a");
+    sys.step_until_no_events();
+
+    let clamped: Vec<_> = records.borrow().iter().filter_map(|r| match &r.kind {
+        LogKind::TimerDelayClamped { name, min, .. } => Some((r.node.clone(), name.clone(), *min)),
+        _ => None,
+    }).collect();
+    assert_eq!(clamped, vec![(Some("a".to_string()), "sleep".to_string(), 0.1)]);
+}
+
+#[test]
+#[should_panic(expected = "Timer interval must be positive")]
+fn zero_periodic_timer_interval_is_rejected() {
+    let (mut sys, _) = system();
+    sys.add_periodic_timer("a", "t", 0.);
+}